@@ -0,0 +1,32 @@
+#!/usr/bin/env bash
+set -euxo pipefail
+
+# Configure a cluster which communicates over Unix domain sockets.
+cat > config-unix.yml <<CONFIG
+nodes:
+  - "unix:$(pwd)/node-0.sock"
+  - "unix:$(pwd)/node-1.sock"
+  - "unix:$(pwd)/node-2.sock"
+CONFIG
+
+# Start the Paxos instances in the background.
+echo 'Starting Paxos instance 0…'
+LOG_LEVEL=debug "$PAXOS" --config-file config-unix.yml --node 0 --propose foo | tee node-0.txt &
+echo 'Starting Paxos instance 1…'
+LOG_LEVEL=debug "$PAXOS" --config-file config-unix.yml --node 1 --propose bar | tee node-1.txt &
+echo 'Starting Paxos instance 2…'
+LOG_LEVEL=debug "$PAXOS" --config-file config-unix.yml --node 2 --propose baz | tee node-2.txt &
+
+# Wait for the nodes to achieve consensus.
+echo 'Waiting for Paxos instance 0…'
+grep -q 'foo\|bar\|baz' <(tail -F node-0.txt)
+echo 'Waiting for Paxos instance 1…'
+grep -q 'foo\|bar\|baz' <(tail -F node-1.txt)
+echo 'Waiting for Paxos instance 2…'
+grep -q 'foo\|bar\|baz' <(tail -F node-2.txt)
+
+# Kill all the subprocesses spawned by this script.
+pkill -P "$$"
+
+# Clean up the files.
+rm -f config-unix.yml node-0.txt node-1.txt node-2.txt node-0.sock node-1.sock node-2.sock
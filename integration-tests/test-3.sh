@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+set -euxo pipefail
+
+# Start the Paxos instances in the background, asking them to exit once a value has been chosen.
+echo 'Starting Paxos instance 0…'
+LOG_LEVEL=debug "$PAXOS" --node 0 --propose foo --exit-after-chosen --linger 1 > node-0.json &
+PID_0="$!"
+echo 'Starting Paxos instance 1…'
+LOG_LEVEL=debug "$PAXOS" --node 1 --propose bar --exit-after-chosen --linger 1 > node-1.json &
+PID_1="$!"
+echo 'Starting Paxos instance 2…'
+LOG_LEVEL=debug "$PAXOS" --node 2 --exit-after-chosen --linger 1 --result-file node-2.json &
+PID_2="$!"
+
+# Wait for the nodes to exit successfully.
+echo 'Waiting for Paxos instance 0…'
+wait "$PID_0"
+echo 'Waiting for Paxos instance 1…'
+wait "$PID_1"
+echo 'Waiting for Paxos instance 2…'
+wait "$PID_2"
+
+# Check that the nodes reported the same result.
+grep -q '"value":' node-0.json
+cmp node-0.json node-1.json
+cmp node-0.json node-2.json
+
+# Clean up the files.
+rm node-0.json node-1.json node-2.json
@@ -0,0 +1,66 @@
+#!/usr/bin/env bash
+set -euxo pipefail
+
+# Cut node 0 off from the others for the first few seconds, and disrupt the rest of the messages.
+# Each node gets its own script, since a proxy only sees the messages sent to its node.
+cat > chaos-0.yml <<SCRIPT
+seed: 0
+rules:
+  - senders: ["127.0.0.1:3001", "127.0.0.1:3002"]
+    end_ms: 3000
+    partition: true
+SCRIPT
+cat > chaos-others.yml <<SCRIPT
+seed: 1
+rules:
+  - senders: ["127.0.0.1:3000"]
+    end_ms: 3000
+    partition: true
+  - drop: 0.1
+    drop_response: 0.1
+    duplicate: 0.2
+    reorder: 0.2
+    max_delay_ms: 50
+SCRIPT
+
+# Put a chaos proxy at the address of each node in the configuration, in front of the node itself.
+echo 'Starting the chaos proxies…'
+"$PAXOS" chaos-proxy --listen 127.0.0.1:3000 --target 127.0.0.1:4000 --script chaos-0.yml &
+PROXY_0="$!"
+"$PAXOS" chaos-proxy --listen 127.0.0.1:3001 --target 127.0.0.1:4001 --script chaos-others.yml &
+PROXY_1="$!"
+"$PAXOS" chaos-proxy --listen 127.0.0.1:3002 --target 127.0.0.1:4002 --script chaos-others.yml &
+PROXY_2="$!"
+
+# Start the Paxos instances in the background, asking them to exit once a value has been chosen.
+echo 'Starting Paxos instance 0…'
+LOG_LEVEL=debug "$PAXOS" --node 0 --port 4000 --data-dir data-chaos --propose foo \
+  --exit-after-chosen --linger 5 > node-0.json &
+PID_0="$!"
+echo 'Starting Paxos instance 1…'
+LOG_LEVEL=debug "$PAXOS" --node 1 --port 4001 --data-dir data-chaos --propose bar \
+  --exit-after-chosen --linger 5 > node-1.json &
+PID_1="$!"
+echo 'Starting Paxos instance 2…'
+LOG_LEVEL=debug "$PAXOS" --node 2 --port 4002 --data-dir data-chaos \
+  --exit-after-chosen --linger 5 > node-2.json &
+PID_2="$!"
+
+# Wait for the nodes to exit successfully.
+echo 'Waiting for Paxos instance 0…'
+wait "$PID_0"
+echo 'Waiting for Paxos instance 1…'
+wait "$PID_1"
+echo 'Waiting for Paxos instance 2…'
+wait "$PID_2"
+
+# Stop the proxies.
+kill "$PROXY_0" "$PROXY_1" "$PROXY_2"
+
+# Check that the nodes reported the same result.
+grep -q '"value":' node-0.json
+cmp node-0.json node-1.json
+cmp node-0.json node-2.json
+
+# Clean up the files.
+rm -rf chaos-0.yml chaos-others.yml data-chaos node-0.json node-1.json node-2.json
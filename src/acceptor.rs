@@ -1,20 +1,40 @@
-use crate::state::{self, ProposalNumber};
+use crate::{
+    address::Address,
+    backoff::Contention,
+    dashboard::{self, CLUSTER_ENDPOINT, Event, Snapshot, Timeline},
+    health::{self, HEALTH_ENDPOINT, PING_ENDPOINT, READINESS_ENDPOINT},
+    instances::Instances,
+    locks::{self, Operation},
+    node::Node,
+    peers::PeerStatus,
+    proposer::{QuorumRead, propose, read_quorum},
+    register::{self, Change, LeaderLease, REGISTER_ENDPOINT, Register},
+    rpc::SENDER_HEADER,
+    state::{self, LeaseGrant, ProposalNumber},
+    store::{self, KV_ENDPOINT},
+    trace::{Kind, TRACEPARENT_HEADER},
+    transport::Listener,
+};
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full};
 use hyper::{
     Method, Request, Response, StatusCode, body::Incoming, header::CONTENT_TYPE,
-    server::conn::http1, service::service_fn,
+    service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto, graceful::GracefulShutdown},
 };
-use hyper_util::rt::{TokioIo, TokioTimer};
 use serde::{Deserialize, Serialize};
 use std::{
     convert::Infallible,
-    io::{self, Write},
-    net::SocketAddr,
-    path::{Path, PathBuf},
-    sync::Arc,
+    io,
+    mem::take,
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, Instant},
 };
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::{sync::oneshot, task::JoinSet, time::timeout};
 
 // We embed the favicon directly into the compiled binary.
 const FAVICON_DATA: &[u8] = include_bytes!("../resources/favicon.ico");
@@ -23,12 +43,25 @@ const FAVICON_DATA: &[u8] = include_bytes!("../resources/favicon.ico");
 pub const PREPARE_ENDPOINT: &str = "/prepare";
 pub const ACCEPT_ENDPOINT: &str = "/accept";
 pub const CHOOSE_ENDPOINT: &str = "/choose";
+pub const CHOSEN_ENDPOINT: &str = "/chosen";
+pub const OPEN_ENDPOINT: &str = "/open";
+pub const FAST_ACCEPT_ENDPOINT: &str = "/fast-accept";
+pub const STATE_ENDPOINT: &str = "/state";
+pub const VALUE_ENDPOINT: &str = "/value";
+
+// The version of the schema of the "state" endpoint's responses. It's incremented whenever a field
+// is removed or its meaning changes, but not when a field is added.
+const STATE_SCHEMA_VERSION: u32 = 1;
 
 // Request type for the "prepare" endpoint
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct PrepareRequest {
     pub proposal_number: Option<ProposalNumber>,
+
+    // How long the proposer would like a leader lease for, if at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_duration_ms: Option<u64>,
 }
 
 // Response type for the "prepare" endpoint
@@ -36,6 +69,49 @@ pub struct PrepareRequest {
 #[serde(deny_unknown_fields)]
 pub struct PrepareResponse {
     pub accepted_proposal: Option<(ProposalNumber, String)>,
+
+    // The chosen proposal, if the node knows it, which saves the proposer the rest of the round
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chosen_proposal: Option<(ProposalNumber, String)>,
+
+    // Whether the node granted a leader lease or refused the request because of one, if applicable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease: Option<LeaseStatus>,
+}
+
+// What a node did about a leader lease in response to a prepare request
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseStatus {
+    // The node promised to refuse proposals from other nodes until the lease expires.
+    Granted,
+
+    // The node made no promise, since it granted a lease to another node which hasn't expired.
+    Refused,
+}
+
+// Determine whether a leader lease granted by this node keeps it from dealing with a proposer. If
+// so, the lease won't be renewed. Leases which have expired are forgotten.
+fn leased_to_another(
+    proposer_address: &Address,
+    state: &mut (state::Durable, state::Volatile),
+) -> bool {
+    if state
+        .1
+        .leader_lease
+        .as_ref()
+        .is_some_and(|lease| lease.expires_at <= Instant::now())
+    {
+        state.1.leader_lease = None;
+    }
+
+    match &mut state.1.leader_lease {
+        Some(lease) if lease.holder.as_ref() != Some(proposer_address) => {
+            lease.contended = true;
+            true
+        }
+        _ => false,
+    }
 }
 
 // Logic for the "prepare" endpoint
@@ -44,25 +120,54 @@ fn prepare(
     state: &mut (state::Durable, state::Volatile),
 ) -> PrepareResponse {
     debug!(
-        "Received prepare request:\n{}",
-        yaml_serde::to_string(request).unwrap(), // Serialization is safe.
+        endpoint = PREPARE_ENDPOINT,
+        round = request.proposal_number.as_ref().map(|proposal_number| proposal_number.round),
+        proposer = request
+            .proposal_number
+            .as_ref()
+            .map(|proposal_number| proposal_number.proposer_address.to_string());
+        "Received prepare request.",
     );
 
-    if let Some(requested_proposal_number) = request.proposal_number {
-        match &state.0.min_proposal_number {
-            Some(proposal_number) => {
-                if requested_proposal_number > *proposal_number {
-                    state.0.min_proposal_number = Some(requested_proposal_number);
-                }
-            }
-            None => {
-                state.0.min_proposal_number = Some(requested_proposal_number);
+    let mut lease = None;
+    if let Some(requested_proposal_number) = &request.proposal_number {
+        if leased_to_another(&requested_proposal_number.proposer_address, state) {
+            return PrepareResponse {
+                accepted_proposal: state.0.accepted_proposal.clone(),
+                chosen_proposal: state.1.chosen_proposal.clone(),
+                lease: Some(LeaseStatus::Refused),
+            };
+        }
+
+        let promised = match &state.0.min_proposal_number {
+            Some(proposal_number) => requested_proposal_number > proposal_number,
+            None => true,
+        };
+        if promised {
+            state.0.min_proposal_number = Some(requested_proposal_number.clone());
+
+            // Grant or renew a leader lease if asked, unless another node has been waiting for it.
+            if let Some(lease_duration_ms) = request.lease_duration_ms
+                && !state
+                    .1
+                    .leader_lease
+                    .as_ref()
+                    .is_some_and(|lease| lease.contended)
+            {
+                state.1.leader_lease = Some(LeaseGrant {
+                    holder: Some(requested_proposal_number.proposer_address.clone()),
+                    expires_at: Instant::now() + Duration::from_millis(lease_duration_ms),
+                    contended: false,
+                });
+                lease = Some(LeaseStatus::Granted);
             }
         }
     }
 
     PrepareResponse {
         accepted_proposal: state.0.accepted_proposal.clone(),
+        chosen_proposal: state.1.chosen_proposal.clone(),
+        lease,
     }
 }
 
@@ -78,6 +183,10 @@ pub struct AcceptRequest {
 #[serde(deny_unknown_fields)]
 pub struct AcceptResponse {
     pub min_proposal_number: ProposalNumber,
+
+    // Whether the node refused the proposal because it granted a leader lease to another node
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub leased: bool,
 }
 
 // Logic for the "accept" endpoint
@@ -86,27 +195,44 @@ fn accept(
     state: &mut (state::Durable, state::Volatile),
 ) -> AcceptResponse {
     debug!(
-        "Received accept request:\n{}",
-        yaml_serde::to_string(request).unwrap(), // Serialization is safe.
+        endpoint = ACCEPT_ENDPOINT,
+        round = request.proposal.0.round,
+        proposer:% = request.proposal.0.proposer_address,
+        value = request.proposal.1;
+        "Received accept request.",
     );
 
+    if leased_to_another(&request.proposal.0.proposer_address, state) {
+        return AcceptResponse {
+            min_proposal_number: state
+                .0
+                .min_proposal_number
+                .clone()
+                .unwrap_or_else(|| request.proposal.0.clone()),
+            leased: true,
+        };
+    }
+
     if state
         .0
         .min_proposal_number
         .as_ref()
         .is_none_or(|proposal_number| request.proposal.0 >= *proposal_number)
     {
-        state.0.min_proposal_number = Some(request.proposal.0);
+        state.0.min_proposal_number = Some(request.proposal.0.clone());
         state.0.accepted_proposal = Some(request.proposal.clone());
     }
 
     AcceptResponse {
         // The `unwrap` is safe since accepts must follow at least one prepare.
-        min_proposal_number: state.0.min_proposal_number.unwrap(),
+        min_proposal_number: state.0.min_proposal_number.clone().unwrap(),
+        leased: false,
     }
 }
 
-// Request type for the "choose" endpoint
+// Request type for the "choose" endpoint. It only has the value, since nodes from before the
+// "chosen" endpoint existed reject requests with any other fields. Proposers send it alongside a
+// "chosen" request so those nodes still learn the value during a rolling upgrade.
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct ChooseRequest {
@@ -118,88 +244,486 @@ pub struct ChooseRequest {
 #[serde(deny_unknown_fields)]
 pub struct ChooseResponse;
 
-// Logic for the "choose" endpoint
+// Logic for the "choose" endpoint. Without the proposal number, the chosen proposal can't be
+// recorded. Newer proposers also send a "chosen" request, and the value sent by an older one is
+// learned by this node's own proposer instead.
 fn choose(
     request: &ChooseRequest,
     state: &mut (state::Durable, state::Volatile),
 ) -> ChooseResponse {
-    if state.1.chosen_value.is_none() {
-        info!("Consensus achieved.");
-        println!("{}", request.value);
-        io::stdout().flush().unwrap_or(());
-        state.1.chosen_value = Some(request.value.clone());
+    if state.1.chosen_proposal.is_none() {
+        debug!(
+            endpoint = CHOOSE_ENDPOINT,
+            value = request.value;
+            "Received a chosen value without the proposal number.",
+        );
     }
     ChooseResponse {}
 }
 
+// Request type for the "chosen" endpoint
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChosenRequest {
+    pub proposal: (ProposalNumber, String),
+}
+
+// Response type for the "chosen" endpoint
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChosenResponse;
+
+// Logic for the "chosen" endpoint
+fn chosen(
+    request: &ChosenRequest,
+    state: &mut (state::Durable, state::Volatile),
+) -> ChosenResponse {
+    if state.1.chosen_proposal.is_none() {
+        info!(
+            endpoint = CHOSEN_ENDPOINT,
+            round = request.proposal.0.round,
+            proposer:% = request.proposal.0.proposer_address,
+            value = request.proposal.1;
+            "Consensus achieved.",
+        );
+        state.1.chosen_proposal = Some(request.proposal.clone());
+    }
+    ChosenResponse {}
+}
+
+// Request type for the "open" endpoint, which a coordinator uses to open a fast ballot once a
+// quorum has promised to it and none of them had accepted a value
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenRequest {
+    pub proposal_number: ProposalNumber,
+}
+
+// Response type for the "open" endpoint
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenResponse {
+    pub min_proposal_number: ProposalNumber,
+}
+
+// Logic for the "open" endpoint. This is like an accept request, except that rather than accepting
+// a particular value, the node agrees to accept the first value a client sends it directly.
+fn open(request: &OpenRequest, state: &mut (state::Durable, state::Volatile)) -> OpenResponse {
+    debug!(
+        endpoint = OPEN_ENDPOINT,
+        round = request.proposal_number.round,
+        proposer:% = request.proposal_number.proposer_address;
+        "Received open request.",
+    );
+
+    if state
+        .0
+        .min_proposal_number
+        .as_ref()
+        .is_none_or(|proposal_number| request.proposal_number >= *proposal_number)
+    {
+        state.0.min_proposal_number = Some(request.proposal_number.clone());
+        state.0.fast_ballot = Some(request.proposal_number.clone());
+    }
+
+    OpenResponse {
+        // The `unwrap` is safe since we just set it if it wasn't already set.
+        min_proposal_number: state.0.min_proposal_number.clone().unwrap(),
+    }
+}
+
+// Request type for the "fast-accept" endpoint, which clients use to send values directly to the
+// nodes in a fast ballot
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FastAcceptRequest {
+    pub value: String,
+}
+
+// Response type for the "fast-accept" endpoint. Clients compare the accepted proposals across the
+// nodes to determine whether their value was chosen.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FastAcceptResponse {
+    pub accepted_proposal: Option<(ProposalNumber, String)>,
+}
+
+// Logic for the "fast-accept" endpoint. A node accepts at most one value in a fast ballot, and only
+// if it hasn't promised to ignore that ballot since it was opened.
+fn fast_accept(
+    request: &FastAcceptRequest,
+    state: &mut (state::Durable, state::Volatile),
+) -> FastAcceptResponse {
+    debug!(
+        endpoint = FAST_ACCEPT_ENDPOINT,
+        value = request.value;
+        "Received fast accept request.",
+    );
+
+    if let Some(fast_ballot) = &state.0.fast_ballot
+        && state.0.min_proposal_number.as_ref() == Some(fast_ballot)
+        && state
+            .0
+            .accepted_proposal
+            .as_ref()
+            .is_none_or(|(proposal_number, _)| proposal_number != fast_ballot)
+    {
+        state.0.accepted_proposal = Some((fast_ballot.clone(), request.value.clone()));
+    }
+
+    FastAcceptResponse {
+        accepted_proposal: state.0.accepted_proposal.clone(),
+    }
+}
+
+// Response type for the "state" endpoint
+#[derive(Serialize)]
+struct StateResponse<'a> {
+    schema_version: u32,
+    node_index: usize,
+    address: &'a Address,
+    nodes: &'a [Address],
+    build_version: &'static str,
+    uptime_seconds: f64,
+    durable: &'a state::Durable,
+    volatile: &'a state::Volatile,
+    events: Vec<Event>,
+    contention: Contention,
+    peers: Vec<PeerStatus>,
+    connections_opened: u64,
+    state_writes: u64,
+}
+
+// The tasks which handle requests that change the state. They run to completion even if their
+// connections are closed, so they're tracked to let the node wait for them before it stops. Once
+// that happens, no more can be started.
+#[derive(Clone)]
+pub struct Handlers(Arc<Mutex<Option<JoinSet<()>>>>);
+
+impl Default for Handlers {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Some(JoinSet::new()))))
+    }
+}
+
+impl Handlers {
+    // Start a task which runs to completion even if the returned future is dropped. The future
+    // resolves to the output of the task, or nothing if the node is stopping or the task panicked.
+    fn run<T: Send + 'static>(
+        &self,
+        task: impl Future<Output = T> + Send + 'static,
+    ) -> impl Future<Output = Option<T>> {
+        let (sender, receiver) = oneshot::channel();
+
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        if let Some(tasks) = self.0.lock().unwrap().as_mut() {
+            // Forget the tasks which have finished.
+            while tasks.try_join_next().is_some() {}
+            tasks.spawn(async move {
+                // Sending only fails if the request was abandoned, in which case there's nothing
+                // to do.
+                let _ = sender.send(task.await);
+            });
+        }
+
+        async move { receiver.await.ok() }
+    }
+
+    // Wait for every task to finish, and keep new ones from starting.
+    pub async fn close(&self) {
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        let mut tasks = take(&mut *self.0.lock().unwrap()).unwrap_or_default();
+        while tasks.join_next().await.is_some() {}
+    }
+
+    // Abort the tasks which are still running when the returned guard is dropped. That's for when
+    // the node is killed rather than stopped, so the tasks don't outlive it.
+    pub fn abort_on_drop(&self) -> AbortOnDrop {
+        AbortOnDrop(self.clone())
+    }
+}
+
+// Aborts the tasks of the handlers when it's dropped
+pub struct AbortOnDrop(Handlers);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        // Dropping the tasks aborts them. That happens after the lock is released. If a panic
+        // already happened, there's nothing to do.
+        drop(self.0.0.lock().map(|mut tasks| take(&mut *tasks)));
+    }
+}
+
 // Context for each service instance
 #[derive(Clone)]
 struct Context {
-    state: Arc<RwLock<(state::Durable, state::Volatile)>>,
-    data_file_path: PathBuf,
+    node: Node,
+    address: Address,
+    started_at: Instant,
+    timeline: Timeline,
+    fast: bool,
+    register: bool,
+    leader_lease: Option<LeaderLease>,
 }
 
 // Request handler
+#[allow(clippy::too_many_lines)]
 async fn handle_request(
     context: Context,
     request: Request<Incoming>,
 ) -> Result<Response<Full<Bytes>>, io::Error> {
     // This macro eliminates some boilerplate in the match expression below.
     macro_rules! rpc {
-        ($endpoint:ident) => {{
-            // Collect the body into a byte array.
-            let body = request
-                .into_body()
-                .collect()
-                .await
-                .map(|body| body.to_bytes())
-                .map_err(|error| {
-                    io::Error::other(format!("Unable to read request body. Reason: {}", error))
+        ($endpoint:ident) => {
+            rpc![
+                $endpoint,
+                None,
+                context.node.state.clone(),
+                Some(context.node.data_file_path.clone())
+            ]
+        };
+        ($endpoint:ident, $instance:expr, $state:expr, $data_file_path:expr) => {{
+            // Continue the trace of the proposer which sent the request, if any.
+            let mut span = context.node.tracer.remote_span(
+                format!("handle {}", request.uri().path()),
+                Kind::Server,
+                request
+                    .headers()
+                    .get(TRACEPARENT_HEADER)
+                    .and_then(|value| value.to_str().ok()),
+            );
+
+            // Find out which node sent the request, if it identified itself.
+            let sender = request
+                .headers()
+                .get(SENDER_HEADER)
+                .and_then(|value| value.to_str().ok()?.parse::<Address>().ok());
+
+            let result = async {
+                // Collect the body into a byte array.
+                let body = request
+                    .into_body()
+                    .collect()
+                    .await
+                    .map(|body| body.to_bytes())
+                    .map_err(|error| {
+                        io::Error::other(format!("Unable to read request body. Reason: {}", error))
+                    })?;
+
+                // Parse the body.
+                let payload = serde_json::from_slice(&body).map_err(|error| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unable to parse request body. Reason: {}", error),
+                    )
                 })?;
+                span.set("request", &String::from_utf8_lossy(&body));
 
-            // Parse the body.
-            let payload = serde_json::from_slice(&body).map_err(|error| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Unable to parse request body. Reason: {}", error),
-                )
-            })?;
+                // Handle the request. This happens in a separate task so it runs to completion even
+                // if the client hangs up (e.g., because a quorum already responded), since the state
+                // file and the audit log must not be left out of sync. Compacted instances have no
+                // state file, and nothing about them is recorded.
+                let context = context.clone();
+                let instance: Option<(Instances, String)> = $instance;
+                let state = $state;
+                let data_file_path: Option<PathBuf> = $data_file_path;
+                let handlers = context.node.handlers.clone();
+                let response = handlers.run(async move {
+                    let mut guard = state.write().await;
+                    let Some(data_file_path) = data_file_path else {
+                        return Ok($endpoint(&payload, &mut guard));
+                    };
+                    let before = Snapshot::of(&guard);
+                    let audit = context.node.audit_log.is_enabled().then(|| {
+                        (context.node.audit_log.turn(), (guard.0.clone(), guard.1.clone()))
+                    });
+                    let was_chosen = guard.1.chosen_proposal.is_some();
+                    let durable_before = guard.0.clone();
+                    let response = $endpoint(&payload, &mut guard);
+                    if guard.0 != durable_before {
+                        guard.1.commits.change();
+                    }
+                    let changes = guard.1.commits.changes();
+                    let newly_chosen = guard
+                        .1
+                        .chosen_proposal
+                        .clone()
+                        .filter(|_| !was_chosen);
+                    if instance.is_none() {
+                        context
+                            .timeline
+                            .record(stringify!($endpoint), &before, &Snapshot::of(&guard));
+                    }
+                    let audit = audit.map(|(turn, before_state)| {
+                        (turn, before_state, (guard.0.clone(), guard.1.clone()))
+                    });
 
-            // Handle the request.
-            let mut guard = context.state.write().await;
-            let response = $endpoint(&payload, &mut guard);
-            crate::state::write(&guard.0, &context.data_file_path).await?;
+                    // Release the state so other requests can make their changes while this one
+                    // waits for its change (or any it saw) to be committed [ref:group_commit]. Only
+                    // then is the transition recorded, so nothing describes a state a crash could
+                    // lose.
+                    drop(guard);
+                    crate::state::commit(&state, changes, &data_file_path).await?;
+                    if let Some((instances, key)) = &instance
+                        && let Some(chosen_proposal) = &newly_chosen
+                    {
+                        instances.record_chosen(key, chosen_proposal).await?;
+                    }
+                    if let Some((turn, before_state, after_state)) = audit {
+                        context
+                            .node
+                            .audit_log
+                            .record(
+                                turn,
+                                sender,
+                                instance
+                                    .as_ref()
+                                    .map(|(instances, key)| instances.name(key))
+                                    .as_deref(),
+                                stringify!($endpoint),
+                                &payload,
+                                before_state,
+                                after_state,
+                            )
+                            .await?;
+                    }
+                    Ok::<_, io::Error>(response)
+                })
+                .await
+                .ok_or_else(|| io::Error::other("Unable to handle request."))??;
 
-            // Serialize the response.
-            Ok(Response::new(Full::new(Bytes::from(
-                serde_json::to_vec(&response).map_err(|error| {
+                // Serialize the response.
+                let response = serde_json::to_vec(&response).map_err(|error| {
                     io::Error::other(format!("Unable to serialize response. Reason: {}", error))
-                })?,
-            ))))
+                })?;
+                span.set("response", &String::from_utf8_lossy(&response));
+                Ok(Response::new(Full::new(Bytes::from(response))))
+            }
+            .await;
+
+            if let Err(error) = &result {
+                span.fail(error);
+            }
+            result
+        }};
+        ($endpoint:ident, $instances:expr, $key:expr) => {{
+            let (instances, key): (Instances, String) = ($instances, $key);
+            match instances.get(&key).await {
+                Ok((state, data_file_path)) => {
+                    rpc![$endpoint, Some((instances, key)), state, data_file_path]
+                }
+                Err(error) => Err(error),
+            }
         }};
     }
 
+    // Named instances only support classic rounds. The locks are registers, which never have a
+    // value chosen, so they don't take "chosen" requests (which would compact a lock for good).
+    if request.method() == Method::POST {
+        for (instances, endpoints) in [
+            (
+                &context.node.instances,
+                &[PREPARE_ENDPOINT, ACCEPT_ENDPOINT, CHOSEN_ENDPOINT][..],
+            ),
+            (
+                &context.node.locks.instances,
+                &[PREPARE_ENDPOINT, ACCEPT_ENDPOINT][..],
+            ),
+        ] {
+            if let Some((key, endpoint)) = instances.route(request.uri().path(), endpoints) {
+                let instances = instances.clone();
+                return match endpoint {
+                    PREPARE_ENDPOINT => rpc![prepare, instances, key],
+                    ACCEPT_ENDPOINT => rpc![accept, instances, key],
+                    _ => rpc![chosen, instances, key],
+                };
+            }
+        }
+    }
+
+    // Locks
+    if let Some(name) = locks::name(request.uri().path()) {
+        let name = name.map(ToOwned::to_owned);
+        return lock_request(&context, request, name).await;
+    }
+
+    // Keys in the key-value store
+    if let Some(key) = store::key(request.uri().path()) {
+        let key = key.map(ToOwned::to_owned);
+        return kv_request(&context, request, key).await;
+    }
+
     // Match on the route and handle the request appropriately.
     match (request.method(), request.uri().path()) {
         // RPC calls
         (&Method::POST, PREPARE_ENDPOINT) => rpc![prepare],
         (&Method::POST, ACCEPT_ENDPOINT) => rpc![accept],
         (&Method::POST, CHOOSE_ENDPOINT) => rpc![choose],
+        (&Method::POST, CHOSEN_ENDPOINT) => rpc![chosen],
+        (&Method::POST, OPEN_ENDPOINT) => rpc![open],
+        (&Method::POST, FAST_ACCEPT_ENDPOINT) => rpc![fast_accept],
+
+        // Dashboard
+        (&Method::GET, "/") => Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Full::new(Bytes::from_static(dashboard::PAGE.as_bytes())))
+            // The `unwrap` is safe since we constructed a well-formed
+            // response.
+            .unwrap()),
 
-        // Summary of the program state
-        (&Method::GET, "/") => {
-            // Respond with a representation of the program state. The `unwrap`s
-            // are safe because serialization should never fail.
-            let state = context.state.read().await;
-            let durable_state_repr = yaml_serde::to_string(&state.0).unwrap();
-            let volatile_state_repr = yaml_serde::to_string(&state.1).unwrap();
-            Ok(Response::new(Full::new(Bytes::from(format!(
-                "System operational.\n\n\
-                Durable state:\n\n\
-                {durable_state_repr}\n\n\
-                Volatile state:\n\n\
-                {volatile_state_repr}",
-            )))))
+        // The state of this node, for tools and for the dashboard on every node
+        (&Method::GET, STATE_ENDPOINT) => {
+            let state = context.node.state.read().await;
+            Ok(json_response(&StateResponse {
+                schema_version: STATE_SCHEMA_VERSION,
+                node_index: context.node.index,
+                address: &context.address,
+                nodes: &context.node.nodes,
+                build_version: env!("CARGO_PKG_VERSION"),
+                uptime_seconds: context.started_at.elapsed().as_secs_f64(),
+                durable: &state.0,
+                volatile: &state.1,
+                events: context.timeline.events(),
+                contention: context.node.backoff.contention(),
+                peers: context.node.backoff.peers().status(),
+                connections_opened: context.node.connections_opened.load(Ordering::Relaxed),
+                state_writes: state.1.commits.writes(),
+            }))
+        }
+
+        // The state of every node, for the dashboard
+        (&Method::GET, CLUSTER_ENDPOINT) => Ok(json_response(
+            &dashboard::cluster(&context.node.client, &context.node.nodes).await,
+        )),
+
+        // The chosen value, unless this node serves a register
+        (&Method::GET, VALUE_ENDPOINT) if !context.register => {
+            value_request(&context, &request).await
+        }
+
+        // The keys in the key-value store
+        (&Method::GET, KV_ENDPOINT) => {
+            Ok(json_response(&store::list(&context.node.instances).await?))
+        }
+
+        // Probes for orchestrators and load balancers
+        (&Method::GET, HEALTH_ENDPOINT) => Ok(probe_response(
+            health::health(&context.node.data_file_path).await,
+        )),
+        (&Method::GET, READINESS_ENDPOINT) => Ok(probe_response(
+            health::readiness(
+                &context.node.client,
+                &context.node.nodes,
+                context.node.index,
+                &context.node.shutdown,
+            )
+            .await,
+        )),
+        (&Method::GET, PING_ENDPOINT) => Ok(json_response(&())),
+
+        // The replicated register, if this node serves one
+        (&Method::GET | &Method::POST, REGISTER_ENDPOINT) if context.register => {
+            register_request(&context, request).await
         }
 
         // Favicon
@@ -226,60 +750,463 @@ async fn handle_request(
     }
 }
 
+// Response type for the "value" endpoint
+#[derive(Serialize)]
+struct ValueResponse<'a> {
+    value: &'a str,
+    proposal_number: &'a ProposalNumber,
+}
+
+// Report the chosen value. By default, the response only reflects what this node knows, so it may
+// be behind the rest of the cluster. With `consistency=linearizable`, a node which doesn't know the
+// value asks a quorum what they've accepted without making them promise anything. That settles the
+// question if they all accepted the same proposal or none of them accepted anything. Otherwise, the
+// node finishes choosing whatever value may have been chosen before responding.
+async fn value_request(
+    context: &Context,
+    request: &Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, io::Error> {
+    let linearizable = match request
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("consistency="))
+    {
+        None | Some("local") => false,
+        Some("linearizable") => true,
+        Some(consistency) => {
+            return Ok(text_response(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "`{consistency}` is not a consistency level. Use `local` or `linearizable`.",
+                ),
+            ));
+        }
+    };
+
+    let respond = |(proposal_number, value): &(ProposalNumber, String)| {
+        json_response(&ValueResponse {
+            value,
+            proposal_number,
+        })
+    };
+    let not_chosen = || {
+        text_response(
+            StatusCode::NOT_FOUND,
+            "No value has been chosen.".to_owned(),
+        )
+    };
+
+    if let Some(chosen_proposal) = &context.node.state.read().await.1.chosen_proposal {
+        return Ok(respond(chosen_proposal));
+    }
+    if !linearizable {
+        return Ok(text_response(
+            StatusCode::NOT_FOUND,
+            "This node doesn't know of a chosen value.".to_owned(),
+        ));
+    }
+
+    match read_quorum(&context.node, "", context.fast).await {
+        None => Ok(shutting_down()),
+        Some(Err(error)) => Ok(text_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            error.to_string(),
+        )),
+        Some(Ok(QuorumRead::Chosen(chosen_proposal))) => Ok(respond(&chosen_proposal)),
+        Some(Ok(QuorumRead::NothingChosen)) => Ok(not_chosen()),
+        Some(Ok(QuorumRead::Unknown)) => {
+            // The round writes to the state file, so it's finished in a task of its own even if the
+            // client hangs up.
+            let node = context.node.clone();
+            let Some(chosen_proposal) = context
+                .node
+                .handlers
+                .run(async move {
+                    propose(&node, node.state.clone(), &node.data_file_path, "", None).await
+                })
+                .await
+            else {
+                return Ok(shutting_down());
+            };
+            let chosen_proposal = chosen_proposal?;
+
+            // Without a value of its own, the proposer only stops before the value is known if a
+            // quorum it prepared had accepted nothing, in which case nothing had been chosen.
+            Ok(match chosen_proposal {
+                Some(chosen_proposal) => respond(&chosen_proposal),
+                None if context.node.shutdown.is_requested() => shutting_down(),
+                None => not_chosen(),
+            })
+        }
+    }
+}
+
+// Response type for the "register" endpoint when a change can't be made
+#[derive(Serialize)]
+struct RegisterRejection {
+    error: String,
+    register: Register,
+}
+
+// Read or change the register. A `GET` request reads it, and a `POST` request changes it according
+// to the body. Either way, the response is the register as of when the request took effect.
+async fn register_request(
+    context: &Context,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, io::Error> {
+    let change = if request.method() == Method::GET {
+        Change::Read
+    } else {
+        let body = request
+            .into_body()
+            .collect()
+            .await
+            .map(http_body_util::Collected::to_bytes)
+            .map_err(|error| {
+                io::Error::other(format!("Unable to read request body. Reason: {error}"))
+            })?;
+        match serde_json::from_slice(&body) {
+            Ok(change) => change,
+            Err(error) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Full::new(Bytes::from(format!(
+                        "Unable to parse request body. Reason: {error}",
+                    ))))
+                    // The `unwrap` is safe since we constructed a well-formed response.
+                    .unwrap());
+            }
+        }
+    };
+
+    // The holder of a leader lease knows the register, since no other node can change it.
+    if matches!(change, Change::Read)
+        && let Some(register) = context.leader_lease.as_ref().and_then(LeaderLease::read)
+    {
+        return Ok(json_response(&register));
+    }
+
+    // The change is made in a task of its own, so a client hanging up can't interrupt a write to the
+    // state file.
+    let node = context.node.clone();
+    let leader_lease = context.leader_lease.clone();
+    let Some(result) = context
+        .node
+        .handlers
+        .run(async move {
+            register::change(
+                &node,
+                node.state.clone(),
+                &node.data_file_path,
+                "",
+                |register, _| change.apply(register),
+                leader_lease.as_ref(),
+            )
+            .await
+        })
+        .await
+    else {
+        return Ok(shutting_down());
+    };
+    let (register, rejection) = result?;
+
+    Ok(match rejection {
+        None => json_response(&register),
+        Some(error) => {
+            let mut response = json_response(&RegisterRejection { error, register });
+            *response.status_mut() = StatusCode::CONFLICT;
+            response
+        }
+    })
+}
+
+// Response type for the "locks" endpoints
+#[derive(Serialize)]
+struct LockResponse {
+    name: String,
+    lease: Option<locks::Lease>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Read a lock with a `GET` request, or perform an operation on it with a `POST` request whose body
+// is the operation. Either way, the response is the lease as of when the request took effect.
+async fn lock_request(
+    context: &Context,
+    request: Request<Incoming>,
+    name: Result<String, String>,
+) -> Result<Response<Full<Bytes>>, io::Error> {
+    let name = match name {
+        Ok(name) => name,
+        Err(error) => return Ok(text_response(StatusCode::BAD_REQUEST, error)),
+    };
+
+    let operation = if request.method() == Method::GET {
+        None
+    } else if request.method() == Method::POST {
+        let body = request
+            .into_body()
+            .collect()
+            .await
+            .map(http_body_util::Collected::to_bytes)
+            .map_err(|error| {
+                io::Error::other(format!("Unable to read request body. Reason: {error}"))
+            })?;
+        match serde_json::from_slice::<Operation>(&body) {
+            Ok(operation) => Some(operation),
+            Err(error) => {
+                return Ok(text_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("Unable to parse request body. Reason: {error}"),
+                ));
+            }
+        }
+    } else {
+        return Ok(text_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Use GET to read a lock or POST to acquire, renew, or release it.".to_owned(),
+        ));
+    };
+
+    // The operation writes to the state file of the lock, so it's performed in a task of its own,
+    // which runs to completion even if the client hangs up.
+    let node = context.node.clone();
+    let task_name = name.clone();
+    let Some(result) = context
+        .node
+        .handlers
+        .run(async move { node.locks.run(&node, &task_name, operation.as_ref()).await })
+        .await
+    else {
+        return Ok(shutting_down());
+    };
+    let (lease, error) = result?;
+
+    let status = if error.is_some() {
+        StatusCode::CONFLICT
+    } else {
+        StatusCode::OK
+    };
+    let mut response = json_response(&LockResponse { name, lease, error });
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+// Read a key in the key-value store with a `GET` request, or propose a value for it with a `PUT`
+// request whose body is the value. Either way, the response is the chosen value, if any.
+async fn kv_request(
+    context: &Context,
+    request: Request<Incoming>,
+    key: Result<String, String>,
+) -> Result<Response<Full<Bytes>>, io::Error> {
+    let key = match key {
+        Ok(key) => key,
+        Err(error) => return Ok(text_response(StatusCode::BAD_REQUEST, error)),
+    };
+
+    // Reading or writing a key may involve a round for its instance, which writes to the instance's
+    // state file. So that's done in a task of its own, which runs to completion even if the client
+    // hangs up.
+    let node = context.node.clone();
+    let task_key = key.clone();
+
+    if request.method() == Method::GET {
+        let value = match context
+            .node
+            .handlers
+            .run(async move { store::get(&node, &task_key).await })
+            .await
+            .flatten()
+        {
+            None => return Ok(shutting_down()),
+            Some(Err(error)) => {
+                return Ok(text_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    error.to_string(),
+                ));
+            }
+            Some(Ok(value)) => value,
+        };
+        let status = if value.is_some() {
+            StatusCode::OK
+        } else {
+            StatusCode::NOT_FOUND
+        };
+        let mut response = json_response(&store::Entry { key, value });
+        *response.status_mut() = status;
+        return Ok(response);
+    }
+
+    if request.method() != Method::PUT {
+        return Ok(text_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Use GET to read a key or PUT to propose a value for it.".to_owned(),
+        ));
+    }
+
+    let body = request
+        .into_body()
+        .collect()
+        .await
+        .map(http_body_util::Collected::to_bytes)
+        .map_err(|error| {
+            io::Error::other(format!("Unable to read request body. Reason: {error}"))
+        })?;
+    let Ok(value) = String::from_utf8(body.to_vec()) else {
+        return Ok(text_response(
+            StatusCode::BAD_REQUEST,
+            "The value must be UTF-8.".to_owned(),
+        ));
+    };
+
+    let task_value = value.clone();
+    let Some(chosen_value) = context
+        .node
+        .handlers
+        .run(async move { store::put(&node, &task_key, &task_value).await })
+        .await
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(shutting_down());
+    };
+
+    // Let the client know if another value was chosen.
+    let status = if chosen_value == value {
+        StatusCode::OK
+    } else {
+        StatusCode::CONFLICT
+    };
+    let mut response = json_response(&store::Entry {
+        key,
+        value: Some(chosen_value),
+    });
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+// Construct a plain text response.
+fn text_response(status: StatusCode, message: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(message)))
+        // The `unwrap` is safe since we constructed a well-formed response.
+        .unwrap()
+}
+
+// Construct the response to a request which can't be handled because the node is shutting down.
+fn shutting_down() -> Response<Full<Bytes>> {
+    text_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "The node is shutting down.".to_owned(),
+    )
+}
+
+// Construct a JSON response.
+fn json_response(payload: &impl Serialize) -> Response<Full<Bytes>> {
+    // The `unwrap`s are safe because serialization should never fail and we constructed a
+    // well-formed response.
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(serde_json::to_vec(payload).unwrap())))
+        .unwrap()
+}
+
+// Construct the response to a health or readiness probe.
+fn probe_response(result: Result<String, String>) -> Response<Full<Bytes>> {
+    let (status, message) = match result {
+        Ok(message) => (StatusCode::OK, message),
+        Err(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
+    };
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(message)))
+        // The `unwrap` is safe since we constructed a well-formed response.
+        .unwrap()
+}
+
 // Entrypoint for the acceptor
 pub async fn acceptor(
-    state: Arc<RwLock<(state::Durable, state::Volatile)>>,
-    data_file_path: &Path,
-    address: SocketAddr,
+    node: &Node,
+    listener: Listener,
+    address: &Address,
+    grace_period: Duration,
+    fast: bool,
+    register: bool,
+    leader_lease: Option<LeaderLease>,
 ) -> Result<(), io::Error> {
     // Set up the HTTP server for the acceptor.
+    let shutdown = &node.shutdown;
     let context = Context {
-        state,
-        data_file_path: data_file_path.to_owned(),
+        node: node.clone(),
+        address: address.clone(),
+        started_at: Instant::now(),
+        timeline: Timeline::default(),
+        fast,
+        register,
+        leader_lease,
     };
-    let listener = TcpListener::bind(address)
-        .await
-        .map_err(|error| io::Error::other(format!("Unable to bind socket. Reason: {error}")))?;
-
     // Tell the user the address of the server.
-    info!("Listening on http://{address}/");
+    match address {
+        Address::Tcp(address) => info!("Listening on http://{address}/"),
+        Address::Unix(path) => info!("Listening on `{}`.", path.to_string_lossy()),
+    }
 
-    loop {
-        let (stream, _) = listener.accept().await.map_err(|error| {
+    // Keep track of the open connections so they can be drained when shutting down. Whichever ones
+    // are still open when the acceptor returns are closed by dropping their tasks.
+    let graceful = GracefulShutdown::new();
+    let mut connections = JoinSet::new();
+
+    // Accept connections until a shutdown is requested.
+    while let Some(result) = shutdown.interrupt(listener.accept()).await {
+        let stream = result.map_err(|error| {
             io::Error::other(format!("Unable to accept connection. Reason: {error}"))
         })?;
 
+        // Forget the connections which have closed.
+        while connections.try_join_next().is_some() {}
+
         let context = context.clone();
+        let watcher = graceful.watcher();
 
-        tokio::spawn(async move {
-            if let Err(error) = http1::Builder::new()
-                .timer(TokioTimer::new())
-                .serve_connection(
-                    TokioIo::new(stream),
-                    service_fn(move |request| {
-                        let context = context.clone();
-
-                        async move {
-                            match handle_request(context, request).await {
-                                Ok(response) => Ok(response),
-                                Err(error) => {
-                                    error!("{error}");
-                                    Ok::<_, Infallible>(
-                                        Response::builder()
-                                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                            .body(Full::new(Bytes::from(error.to_string())))
-                                            .unwrap(),
-                                    )
-                                }
+        connections.spawn(async move {
+            // Clients may use either HTTP/1.1 or HTTP/2 (without TLS, so they have to know in
+            // advance that the server supports it).
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder.http1().timer(TokioTimer::new());
+            builder.http2().timer(TokioTimer::new());
+            let connection = builder.serve_connection(
+                TokioIo::new(stream),
+                service_fn(move |request| {
+                    let context = context.clone();
+
+                    async move {
+                        match handle_request(context, request).await {
+                            Ok(response) => Ok(response),
+                            Err(error) => {
+                                error!("{error}");
+                                Ok::<_, Infallible>(
+                                    Response::builder()
+                                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                        .body(Full::new(Bytes::from(error.to_string())))
+                                        .unwrap(),
+                                )
                             }
                         }
-                    }),
-                )
-                .await
-            {
-                if error.is_incomplete_message() {
-                    // Proposers stop waiting once a quorum responds, which can drop the extra
-                    // in-flight HTTP requests before the peer finishes reading them.
+                    }
+                }),
+            );
+
+            if let Err(error) = watcher.watch(connection).await {
+                if error
+                    .downcast_ref::<hyper::Error>()
+                    .is_some_and(hyper::Error::is_incomplete_message)
+                {
+                    // Proposers let the requests which didn't make it into a quorum linger for a
+                    // while, but then they drop them, possibly before the peer finishes reading
+                    // them.
                     trace!("Connection closed before message completed.");
                 } else {
                     info!("Connection failed. Reason: {error}");
@@ -287,15 +1214,40 @@ pub async fn acceptor(
             }
         });
     }
+
+    // Let the open connections finish the requests they're handling, up to the grace period.
+    // Requests that are still running after that are abandoned, but any changes they're making to
+    // the state files are finished before the node stops [ref:no_torn_state_writes].
+    if timeout(grace_period, graceful.shutdown()).await.is_err() {
+        warn!("Some connections were still open at the end of the grace period.");
+    }
+    connections.shutdown().await;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        acceptor::{AcceptRequest, ChooseRequest, PrepareRequest, accept, choose, prepare},
-        state::{ProposalNumber, initial},
+        acceptor::{
+            AcceptRequest, ChooseRequest, ChosenRequest, FastAcceptRequest, Handlers, LeaseStatus,
+            OpenRequest, PrepareRequest, STATE_SCHEMA_VERSION, StateResponse, accept, choose,
+            chosen, fast_accept, open, prepare,
+        },
+        address::Address,
+        backoff::Contention,
+        state::{LeaseGrant, ProposalNumber, initial},
+    };
+    use serde::Deserialize;
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::{Duration, Instant},
     };
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use tokio::time::sleep;
 
     #[test]
     fn prepare_initializes_min_proposal_number() {
@@ -303,8 +1255,12 @@ mod tests {
         let request = PrepareRequest {
             proposal_number: Some(ProposalNumber {
                 round: 0,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             }),
+            lease_duration_ms: None,
         };
         let response = prepare(&request, &mut state);
         assert_eq!(state.0.min_proposal_number, request.proposal_number);
@@ -316,13 +1272,17 @@ mod tests {
         let mut state = initial();
         state.0.min_proposal_number = Some(ProposalNumber {
             round: 0,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
         });
         let request = PrepareRequest {
             proposal_number: Some(ProposalNumber {
                 round: 1,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             }),
+            lease_duration_ms: None,
         };
         let response = prepare(&request, &mut state);
         assert_eq!(state.0.min_proposal_number, request.proposal_number);
@@ -334,13 +1294,17 @@ mod tests {
         let mut state = initial();
         state.0.min_proposal_number = Some(ProposalNumber {
             round: 1,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
         });
         let request = PrepareRequest {
             proposal_number: Some(ProposalNumber {
                 round: 0,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             }),
+            lease_duration_ms: None,
         };
         let response = prepare(&request, &mut state);
         assert_ne!(state.0.min_proposal_number, request.proposal_number);
@@ -353,35 +1317,85 @@ mod tests {
         let accepted_proposal = (
             ProposalNumber {
                 round: 0,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             },
             "foo".to_string(),
         );
-        state.0.min_proposal_number = Some(accepted_proposal.0);
+        state.0.min_proposal_number = Some(accepted_proposal.0.clone());
         state.0.accepted_proposal = Some(accepted_proposal.clone());
         let request = PrepareRequest {
             proposal_number: Some(ProposalNumber {
                 round: 1,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             }),
+            lease_duration_ms: None,
         };
         let response = prepare(&request, &mut state);
         assert_eq!(response.accepted_proposal, Some(accepted_proposal));
     }
 
+    #[test]
+    fn prepare_omits_unknown_chosen_proposal() {
+        let mut state = initial();
+        let proposal_number = ProposalNumber {
+            round: 0,
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
+        };
+        state.0.min_proposal_number = Some(proposal_number.clone());
+        state.0.accepted_proposal = Some((proposal_number, "foo".to_string()));
+        let request = PrepareRequest {
+            proposal_number: None,
+            lease_duration_ms: None,
+        };
+        let response = prepare(&request, &mut state);
+        assert_eq!(response.chosen_proposal, None);
+    }
+
+    #[test]
+    fn prepare_returns_chosen_proposal() {
+        let mut state = initial();
+        let chosen_proposal = (
+            ProposalNumber {
+                round: 0,
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
+            },
+            "foo".to_string(),
+        );
+        state.1.chosen_proposal = Some(chosen_proposal.clone());
+        let request = PrepareRequest {
+            proposal_number: None,
+            lease_duration_ms: None,
+        };
+        let response = prepare(&request, &mut state);
+        assert_eq!(response.chosen_proposal, Some(chosen_proposal));
+    }
+
     #[test]
     fn accept_success() {
         let mut state = initial();
         let proposal = (
             ProposalNumber {
                 round: 0,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             },
             "foo".to_string(),
         );
 
         let prepare_request = PrepareRequest {
-            proposal_number: Some(proposal.0),
+            proposal_number: Some(proposal.0.clone()),
+            lease_duration_ms: None,
         };
         prepare(&prepare_request, &mut state);
 
@@ -401,7 +1415,10 @@ mod tests {
         let proposal0 = (
             ProposalNumber {
                 round: 0,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8080,
+                )),
             },
             "foo".to_string(),
         );
@@ -409,18 +1426,23 @@ mod tests {
         let proposal1 = (
             ProposalNumber {
                 round: 1,
-                proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081),
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    8081,
+                )),
             },
             "bar".to_string(),
         );
 
         let prepare_request1 = PrepareRequest {
-            proposal_number: Some(proposal0.0),
+            proposal_number: Some(proposal0.0.clone()),
+            lease_duration_ms: None,
         };
         prepare(&prepare_request1, &mut state);
 
         let prepare_request2 = PrepareRequest {
-            proposal_number: Some(proposal1.0),
+            proposal_number: Some(proposal1.0.clone()),
+            lease_duration_ms: None,
         };
         prepare(&prepare_request2, &mut state);
 
@@ -435,12 +1457,284 @@ mod tests {
     }
 
     #[test]
-    fn choose_updates_state() {
+    fn leader_lease_refuses_other_proposers() {
         let mut state = initial();
-        let request = ChooseRequest {
-            value: "foo".to_string(),
+        let holder = Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        let other = Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081));
+        let proposal_number = |round, proposer_address: &Address| ProposalNumber {
+            round,
+            proposer_address: proposer_address.clone(),
+        };
+
+        let response = prepare(
+            &PrepareRequest {
+                proposal_number: Some(proposal_number(0, &holder)),
+                lease_duration_ms: Some(60_000),
+            },
+            &mut state,
+        );
+        assert_eq!(response.lease, Some(LeaseStatus::Granted));
+
+        // Other proposers are refused, even with higher proposal numbers.
+        let response = prepare(
+            &PrepareRequest {
+                proposal_number: Some(proposal_number(1, &other)),
+                lease_duration_ms: Some(60_000),
+            },
+            &mut state,
+        );
+        assert_eq!(response.lease, Some(LeaseStatus::Refused));
+        assert_eq!(
+            state.0.min_proposal_number,
+            Some(proposal_number(0, &holder))
+        );
+        let response = accept(
+            &AcceptRequest {
+                proposal: (proposal_number(1, &other), "foo".to_string()),
+            },
+            &mut state,
+        );
+        assert!(response.leased);
+        assert_eq!(state.0.accepted_proposal, None);
+
+        // The holder can still make proposals, but the lease isn't renewed, since another proposer
+        // has been waiting for it.
+        let response = prepare(
+            &PrepareRequest {
+                proposal_number: Some(proposal_number(2, &holder)),
+                lease_duration_ms: Some(60_000),
+            },
+            &mut state,
+        );
+        assert_eq!(response.lease, None);
+        let response = accept(
+            &AcceptRequest {
+                proposal: (proposal_number(2, &holder), "bar".to_string()),
+            },
+            &mut state,
+        );
+        assert!(!response.leased);
+        assert_eq!(
+            state.0.accepted_proposal,
+            Some((proposal_number(2, &holder), "bar".to_string())),
+        );
+    }
+
+    #[test]
+    fn expired_leader_lease_is_forgotten() {
+        let mut state = initial();
+        let address = Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        let request = PrepareRequest {
+            proposal_number: Some(ProposalNumber {
+                round: 0,
+                proposer_address: address,
+            }),
+            lease_duration_ms: None,
+        };
+
+        // A lease with an unknown holder refuses everyone.
+        state.1.leader_lease = Some(LeaseGrant {
+            holder: None,
+            expires_at: Instant::now() + Duration::from_mins(1),
+            contended: true,
+        });
+        assert_eq!(
+            prepare(&request, &mut state).lease,
+            Some(LeaseStatus::Refused),
+        );
+
+        state.1.leader_lease = Some(LeaseGrant {
+            holder: None,
+            expires_at: Instant::now(),
+            contended: true,
+        });
+        assert_eq!(prepare(&request, &mut state).lease, None);
+        assert!(state.1.leader_lease.is_none());
+        assert_eq!(state.0.min_proposal_number, request.proposal_number);
+    }
+
+    #[test]
+    fn fast_accept_requires_open_ballot() {
+        let mut state = initial();
+        let response = fast_accept(
+            &FastAcceptRequest {
+                value: "foo".to_string(),
+            },
+            &mut state,
+        );
+        assert_eq!(response.accepted_proposal, None);
+        assert_eq!(state.0.accepted_proposal, None);
+    }
+
+    #[test]
+    fn fast_accept_accepts_first_value() {
+        let mut state = initial();
+        let proposal_number = ProposalNumber {
+            round: 0,
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
         };
-        choose(&request, &mut state);
-        assert_eq!(state.1.chosen_value, Some(request.value));
+        open(
+            &OpenRequest {
+                proposal_number: proposal_number.clone(),
+            },
+            &mut state,
+        );
+        fast_accept(
+            &FastAcceptRequest {
+                value: "foo".to_string(),
+            },
+            &mut state,
+        );
+        let response = fast_accept(
+            &FastAcceptRequest {
+                value: "bar".to_string(),
+            },
+            &mut state,
+        );
+        assert_eq!(
+            response.accepted_proposal,
+            Some((proposal_number, "foo".to_string())),
+        );
+    }
+
+    #[test]
+    fn fast_accept_respects_later_promise() {
+        let mut state = initial();
+        let address = Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        open(
+            &OpenRequest {
+                proposal_number: ProposalNumber {
+                    round: 0,
+                    proposer_address: address.clone(),
+                },
+            },
+            &mut state,
+        );
+        prepare(
+            &PrepareRequest {
+                proposal_number: Some(ProposalNumber {
+                    round: 1,
+                    proposer_address: address,
+                }),
+                lease_duration_ms: None,
+            },
+            &mut state,
+        );
+        let response = fast_accept(
+            &FastAcceptRequest {
+                value: "foo".to_string(),
+            },
+            &mut state,
+        );
+        assert_eq!(response.accepted_proposal, None);
+    }
+
+    #[test]
+    fn chosen_updates_state() {
+        let mut state = initial();
+        let request = ChosenRequest {
+            proposal: (
+                ProposalNumber {
+                    round: 0,
+                    proposer_address: Address::Tcp(SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        8080,
+                    )),
+                },
+                "foo".to_string(),
+            ),
+        };
+        chosen(&request, &mut state);
+        assert_eq!(state.1.chosen_proposal, Some(request.proposal));
+    }
+
+    #[test]
+    fn choose_requests_are_understood_by_older_nodes() {
+        // The request type from before the "chosen" endpoint existed
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct OldChooseRequest {
+            value: String,
+        }
+
+        let request = serde_json::to_string(&ChooseRequest {
+            value: "foo".to_string(),
+        })
+        .unwrap();
+        let old_request: OldChooseRequest = serde_json::from_str(&request).unwrap();
+        assert_eq!(old_request.value, "foo");
+
+        // Without the proposal number, the value isn't recorded.
+        let mut state = initial();
+        choose(&serde_json::from_str(&request).unwrap(), &mut state);
+        assert_eq!(state.1.chosen_proposal, None);
+    }
+
+    #[test]
+    fn state_response_schema() {
+        let state = initial();
+        let address = Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+        let response = serde_json::to_value(StateResponse {
+            schema_version: STATE_SCHEMA_VERSION,
+            node_index: 0,
+            address: &address,
+            nodes: std::slice::from_ref(&address),
+            build_version: env!("CARGO_PKG_VERSION"),
+            uptime_seconds: 0.0,
+            durable: &state.0,
+            volatile: &state.1,
+            events: vec![],
+            contention: Contention {
+                rounds: 0,
+                rounds_lost: 0,
+                yields: 0,
+                backoff_seconds: 0.0_f64,
+            },
+            peers: vec![],
+            connections_opened: 0,
+            state_writes: 0,
+        })
+        .unwrap();
+
+        // Clients depend on these fields, so changing them requires a new schema version.
+        assert_eq!(response["schema_version"], 1_u32);
+        assert_eq!(response["node_index"], 0_usize);
+        assert_eq!(response["address"], "127.0.0.1:8080");
+        assert_eq!(response["nodes"][0], "127.0.0.1:8080");
+        assert_eq!(response["durable"]["next_round"], 0_u64);
+        assert_eq!(
+            response["durable"]["min_proposal_number"],
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            response["durable"]["accepted_proposal"],
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            response["volatile"]["chosen_proposal"],
+            serde_json::Value::Null
+        );
+        assert!(response["uptime_seconds"].is_f64());
+        assert!(response["events"].is_array());
+    }
+
+    #[tokio::test]
+    async fn handlers_finish_before_closing() {
+        let handlers = Handlers::default();
+        let finished = Arc::new(AtomicBool::new(false));
+
+        // The task keeps going even though nobody waits for its output.
+        drop(handlers.run({
+            let finished = finished.clone();
+            async move {
+                sleep(Duration::from_millis(50)).await;
+                finished.store(true, Ordering::SeqCst);
+            }
+        }));
+        handlers.close().await;
+        assert!(finished.load(Ordering::SeqCst));
+
+        // No more tasks can be started.
+        assert_eq!(handlers.run(async { 42_u8 }).await, None);
     }
 }
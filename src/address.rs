@@ -0,0 +1,107 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::{fmt, io, net::SocketAddr, path::PathBuf, str::FromStr};
+
+// Addresses with this prefix refer to Unix domain sockets.
+const UNIX_PREFIX: &str = "unix:";
+
+// The address of a node, which is either a TCP socket address or the path of a Unix domain socket.
+// The derived ordering is relied upon by [ref:proposal_number_ord], so the TCP variant must remain
+// first to keep the ordering of TCP addresses unchanged.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.to_string_lossy()),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(raw_address: &str) -> io::Result<Self> {
+        if let Some(path) = raw_address.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{raw_address}` is missing a socket path."),
+                ));
+            }
+
+            Ok(Self::Unix(PathBuf::from(path)))
+        } else {
+            raw_address.parse().map(Self::Tcp).map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "`{raw_address}` is neither a socket address nor a path prefixed with \
+                        `{UNIX_PREFIX}`. Reason: {error}",
+                    ),
+                )
+            })
+        }
+    }
+}
+
+// Addresses are serialized as strings. TCP addresses use the same representation as `SocketAddr`,
+// so data files written before Unix domain sockets were supported can still be read.
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::Address;
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::PathBuf,
+    };
+
+    #[test]
+    fn parse_tcp() {
+        assert_eq!(
+            "127.0.0.1:3000".parse::<Address>().unwrap(),
+            Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)),
+        );
+    }
+
+    #[test]
+    fn parse_unix() {
+        assert_eq!(
+            "unix:/tmp/paxos-0.sock".parse::<Address>().unwrap(),
+            Address::Unix(PathBuf::from("/tmp/paxos-0.sock")),
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("localhost".parse::<Address>().is_err());
+        assert!("unix:".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for raw_address in ["192.168.0.1:3000", "[::1]:3000", "unix:paxos.sock"] {
+            assert_eq!(
+                raw_address.parse::<Address>().unwrap().to_string(),
+                raw_address,
+            );
+        }
+    }
+}
@@ -0,0 +1,669 @@
+use crate::{
+    address::Address,
+    state::{self, ProposalNumber},
+};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{File, OpenOptions, read_to_string},
+    io::AsyncWriteExt,
+    sync::{Mutex, oneshot},
+};
+
+// The state of an acceptor before or after it handled a request
+#[derive(Clone, Deserialize, Serialize)]
+pub struct State {
+    pub durable: state::Durable,
+    pub volatile: state::Volatile,
+}
+
+// An entry in the audit log, which is written as a single line of JSON. The sender is the node which
+// sent the request, if it identified itself. Requests for named instances record the name of the
+// instance.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub timestamp: u64,
+    pub sender: Option<Address>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub endpoint: String,
+    pub request: Value,
+    pub before: State,
+    pub after: State,
+}
+
+// A handle for appending to the audit log, if there is one. The entries are written after the state
+// changes they describe are committed, which can finish in any order, so each entry waits for the
+// one before it.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+    last_turn: Arc<std::sync::Mutex<Option<oneshot::Receiver<Handoff>>>>,
+}
+
+// A place in the order of the entries in the audit log
+pub struct Turn {
+    previous: Option<oneshot::Receiver<Handoff>>,
+    done: Option<oneshot::Sender<Handoff>>,
+}
+
+// Sent to the next entry when a turn is over. If the turn was given up before its entry was
+// recorded, the next entry has to wait for the one before it instead.
+struct Handoff(Option<oneshot::Receiver<Handoff>>);
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            // Sending only fails if the next turn was given up too, which is fine.
+            let _ = done.send(Handoff(self.previous.take()));
+        }
+    }
+}
+
+impl AuditLog {
+    // Open the audit log for appending, if a path is given.
+    pub async fn open(path: Option<&Path>) -> io::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!(
+                        "Unable to open audit log `{}`. Reason: {}",
+                        path.to_string_lossy(),
+                        error,
+                    ),
+                )
+            })?;
+
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(file))),
+            last_turn: Arc::default(),
+        })
+    }
+
+    // Determine whether requests should be recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    // Take the next place in the order of the entries. This is called while the state is locked, so
+    // the entries are in the same order as the transitions. If the turn is dropped without being
+    // used, the next entry only waits for the ones before it.
+    pub fn turn(&self) -> Turn {
+        let (done, next) = oneshot::channel();
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        let previous = self.last_turn.lock().unwrap().replace(next);
+        Turn {
+            previous,
+            done: Some(done),
+        }
+    }
+
+    // Record a request and the state transition it caused, once the entries before it have been
+    // recorded. This is called once the new state has been committed, so the log never describes a
+    // state which was lost in a crash. Like the state file, the log is synced to disk before the
+    // response is sent.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn record(
+        &self,
+        mut turn: Turn,
+        sender: Option<Address>,
+        instance: Option<&str>,
+        endpoint: &str,
+        request: &impl Serialize,
+        before: (state::Durable, state::Volatile),
+        after: (state::Durable, state::Volatile),
+    ) -> io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        // The `unwrap`s are safe because serialization should never fail.
+        let mut line = serde_json::to_vec(&Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| {
+                    duration.as_millis().try_into().unwrap_or(u64::MAX)
+                }),
+            sender,
+            instance: instance.map(ToOwned::to_owned),
+            endpoint: endpoint.to_owned(),
+            request: serde_json::to_value(request).unwrap(),
+            before: State {
+                durable: before.0,
+                volatile: before.1,
+            },
+            after: State {
+                durable: after.0,
+                volatile: after.1,
+            },
+        })
+        .unwrap();
+        line.push(b'\n');
+
+        // Wait for the entries before this one.
+        let mut previous = turn.previous.take();
+        while let Some(receiver) = previous {
+            previous = receiver.await.ok().and_then(|Handoff(earlier)| earlier);
+        }
+
+        let mut file = file.lock().await;
+        file.write_all(&line).await?;
+        file.sync_data().await.map_err(|error| {
+            io::Error::other(format!("Unable to write to audit log. Reason: {error}"))
+        })
+    }
+}
+
+// Print the given audit logs and check them against the invariants of Paxos. Each log should come
+// from a different node. Returns whether the logs are consistent with the protocol.
+pub async fn inspect(paths: &[PathBuf]) -> io::Result<bool> {
+    let mut logs = vec![];
+    let mut violations = vec![];
+
+    for path in paths {
+        let contents = read_to_string(path).await.map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to read audit log `{}`. Reason: {}",
+                    path.to_string_lossy(),
+                    error,
+                ),
+            )
+        })?;
+
+        println!("{}:", path.to_string_lossy());
+        let mut entries = vec![];
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => {
+                    println!("{}", describe(&entry));
+                    entries.push((index + 1, entry));
+                }
+                Err(error) => violations.push(format!(
+                    "{}:{}: The entry is malformed. Reason: {}",
+                    path.to_string_lossy(),
+                    index + 1,
+                    error,
+                )),
+            }
+        }
+        println!();
+
+        logs.push((path.to_string_lossy().into_owned(), entries));
+    }
+
+    violations.extend(verify(&logs));
+
+    let entry_count = logs.iter().map(|(_, entries)| entries.len()).sum::<usize>();
+    if violations.is_empty() {
+        println!("No invariant violations found in {entry_count} entries.");
+        Ok(true)
+    } else {
+        println!("Found {} problem(s):", violations.len());
+        for violation in &violations {
+            println!("  {violation}");
+        }
+        Ok(false)
+    }
+}
+
+// Check audit logs against the invariants of Paxos. Each log is a name (for error messages) and a
+// list of entries with their line numbers. Each instance is checked separately.
+fn verify(logs: &[(String, Vec<(usize, Entry)>)]) -> Vec<String> {
+    let instances = logs
+        .iter()
+        .flat_map(|(_, entries)| entries.iter().map(|(_, entry)| &entry.instance))
+        .collect::<BTreeSet<_>>();
+
+    let mut violations = vec![];
+    for instance in instances {
+        let instance_logs = logs
+            .iter()
+            .map(|(name, entries)| {
+                (
+                    name.clone(),
+                    entries
+                        .iter()
+                        .filter(|(_, entry)| entry.instance == *instance)
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (name, entries) in &instance_logs {
+            verify_acceptor(name, entries, &mut violations);
+        }
+        verify_agreement(&instance_logs, &mut violations);
+    }
+    violations
+}
+
+// Check that an acceptor kept its promises.
+fn verify_acceptor(name: &str, entries: &[(usize, Entry)], violations: &mut Vec<String>) {
+    let mut previous: Option<&Entry> = None;
+    for (line, entry) in entries {
+        let mut violation = |message: String| violations.push(format!("{name}:{line}: {message}"));
+        let before = &entry.before.durable;
+        let after = &entry.after.durable;
+
+        // Only the acceptor changes these fields, so they should pick up where the previous
+        // entry left off. (The proposer changes `next_round`, and `volatile` is reset on restart.)
+        if let Some(previous) = previous
+            && (before.min_proposal_number != previous.after.durable.min_proposal_number
+                || before.accepted_proposal != previous.after.durable.accepted_proposal)
+        {
+            violation(
+                "The state changed since the previous entry without being recorded.".to_owned(),
+            );
+        }
+
+        if after.min_proposal_number < before.min_proposal_number {
+            violation(format!(
+                "The promise went backward from {} to {}.",
+                describe_proposal_number(before.min_proposal_number.as_ref()),
+                describe_proposal_number(after.min_proposal_number.as_ref()),
+            ));
+        }
+
+        if after.accepted_proposal != before.accepted_proposal {
+            match &after.accepted_proposal {
+                Some((proposal_number, _))
+                    if before.min_proposal_number.as_ref() > Some(proposal_number) =>
+                {
+                    violation(format!(
+                        "A proposal from {} was accepted despite a promise to ignore proposals \
+                            below {}.",
+                        describe_proposal_number(Some(proposal_number)),
+                        describe_proposal_number(before.min_proposal_number.as_ref()),
+                    ));
+                }
+                Some(_) => {}
+                None => violation("An accepted proposal was forgotten.".to_owned()),
+            }
+        }
+
+        if let Some((proposal_number, _)) = &after.accepted_proposal
+            && after.min_proposal_number.as_ref() < Some(proposal_number)
+        {
+            violation(format!(
+                "The accepted proposal from {} is above the promise, {}.",
+                describe_proposal_number(Some(proposal_number)),
+                describe_proposal_number(after.min_proposal_number.as_ref()),
+            ));
+        }
+
+        if entry.before.volatile.chosen_proposal.is_some()
+            && entry.after.volatile.chosen_proposal != entry.before.volatile.chosen_proposal
+        {
+            violation("The chosen value changed.".to_owned());
+        }
+
+        previous = Some(entry);
+    }
+}
+
+// Check that the cluster agreed. Every node which learned a value must have learned the same one,
+// and once a value is chosen, every proposal accepted with a greater number must have that value.
+// (Proposals with the same number can have different values in a fast ballot.)
+fn verify_agreement(logs: &[(String, Vec<(usize, Entry)>)], violations: &mut Vec<String>) {
+    let chosen_proposals = logs.iter().flat_map(|(name, entries)| {
+        entries.iter().filter_map(move |(line, entry)| {
+            entry
+                .after
+                .volatile
+                .chosen_proposal
+                .as_ref()
+                .map(|proposal| (name, line, proposal))
+        })
+    });
+    let accepted_proposals = || {
+        logs.iter().flat_map(|(name, entries)| {
+            entries.iter().filter_map(move |(line, entry)| {
+                entry
+                    .after
+                    .durable
+                    .accepted_proposal
+                    .as_ref()
+                    .map(|proposal| (name, line, proposal))
+            })
+        })
+    };
+    let mut first_chosen_proposal: Option<(&String, &usize, &(ProposalNumber, String))> = None;
+    for (name, line, chosen_proposal) in chosen_proposals {
+        if let Some((first_name, first_line, first_chosen_proposal)) = first_chosen_proposal {
+            if chosen_proposal.1 != first_chosen_proposal.1 {
+                violations.push(format!(
+                    "{name}:{line}: `{}` was chosen, but {first_name}:{first_line} says `{}` was \
+                        chosen.",
+                    chosen_proposal.1, first_chosen_proposal.1,
+                ));
+            }
+        } else {
+            first_chosen_proposal = Some((name, line, chosen_proposal));
+
+            for (accepted_name, accepted_line, accepted_proposal) in accepted_proposals() {
+                if accepted_proposal.0 > chosen_proposal.0
+                    && accepted_proposal.1 != chosen_proposal.1
+                {
+                    violations.push(format!(
+                        "{accepted_name}:{accepted_line}: `{}` was accepted from {}, but `{}` was \
+                            already chosen from {} according to {name}:{line}.",
+                        accepted_proposal.1,
+                        describe_proposal_number(Some(&accepted_proposal.0)),
+                        chosen_proposal.1,
+                        describe_proposal_number(Some(&chosen_proposal.0)),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// Describe an entry for humans.
+fn describe(entry: &Entry) -> String {
+    let timestamp = i64::try_from(entry.timestamp)
+        .ok()
+        .and_then(|milliseconds| Timestamp::from_millisecond(milliseconds).ok())
+        .map_or_else(
+            || entry.timestamp.to_string(),
+            |timestamp| timestamp.to_string(),
+        );
+    let sender = entry.sender.as_ref().map_or_else(
+        || "an unknown node".to_owned(),
+        |sender| format!("node {sender}"),
+    );
+    let instance = entry
+        .instance
+        .as_ref()
+        .map_or_else(String::new, |instance| {
+            format!(" for instance `{instance}`")
+        });
+    let mut lines = vec![format!(
+        "  {timestamp} {}{instance} from {sender}: {}",
+        entry.endpoint, entry.request,
+    )];
+
+    let (before, after) = (&entry.before, &entry.after);
+    if after.durable.min_proposal_number != before.durable.min_proposal_number {
+        lines.push(format!(
+            "    promise: {} -> {}",
+            describe_proposal_number(before.durable.min_proposal_number.as_ref()),
+            describe_proposal_number(after.durable.min_proposal_number.as_ref()),
+        ));
+    }
+    if after.durable.accepted_proposal != before.durable.accepted_proposal {
+        lines.push(format!(
+            "    accepted: {} -> {}",
+            describe_proposal(before.durable.accepted_proposal.as_ref()),
+            describe_proposal(after.durable.accepted_proposal.as_ref()),
+        ));
+    }
+    if after.volatile.chosen_proposal != before.volatile.chosen_proposal {
+        lines.push(format!(
+            "    chosen: {} -> {}",
+            describe_proposal(before.volatile.chosen_proposal.as_ref()),
+            describe_proposal(after.volatile.chosen_proposal.as_ref()),
+        ));
+    }
+    if lines.len() == 1 {
+        lines.push("    (no change)".to_owned());
+    }
+
+    lines.join("\n")
+}
+
+// Describe a proposal number for humans.
+fn describe_proposal_number(proposal_number: Option<&ProposalNumber>) -> String {
+    proposal_number.map_or_else(
+        || "none".to_owned(),
+        |proposal_number| {
+            format!(
+                "round {} by {}",
+                proposal_number.round, proposal_number.proposer_address,
+            )
+        },
+    )
+}
+
+// Describe a proposal for humans.
+fn describe_proposal(proposal: Option<&(ProposalNumber, String)>) -> String {
+    proposal.map_or_else(
+        || "none".to_owned(),
+        |(proposal_number, value)| {
+            format!(
+                "`{value}` ({})",
+                describe_proposal_number(Some(proposal_number)),
+            )
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        audit::{AuditLog, Entry, State, verify},
+        state::{ProposalNumber, initial},
+        test_dir::TestDir,
+    };
+    use serde_json::Value;
+    use std::{
+        fs::read_to_string,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+    };
+
+    fn proposal_number(round: u64) -> ProposalNumber {
+        ProposalNumber {
+            round,
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)),
+        }
+    }
+
+    // The round of the promise, the round and value of the accepted proposal, and the round and
+    // value of the chosen proposal
+    type Summary = (
+        Option<u64>,
+        Option<(u64, &'static str)>,
+        Option<(u64, &'static str)>,
+    );
+
+    // Construct an entry for a transition between two states.
+    fn entry(before: Summary, after: Summary) -> Entry {
+        let state = |(promise, accepted, chosen): Summary| {
+            let mut state = initial();
+            state.0.min_proposal_number = promise.map(proposal_number);
+            state.0.accepted_proposal =
+                accepted.map(|(round, value)| (proposal_number(round), value.to_owned()));
+            state.1.chosen_proposal =
+                chosen.map(|(round, value)| (proposal_number(round), value.to_owned()));
+            State {
+                durable: state.0,
+                volatile: state.1,
+            }
+        };
+        Entry {
+            timestamp: 0,
+            sender: None,
+            instance: None,
+            endpoint: "accept".to_owned(),
+            request: Value::Null,
+            before: state(before),
+            after: state(after),
+        }
+    }
+
+    #[test]
+    fn consistent_logs() {
+        let logs = vec![
+            (
+                "0".to_owned(),
+                vec![
+                    (1, entry((None, None, None), (Some(0), None, None))),
+                    (
+                        2,
+                        entry((Some(0), None, None), (Some(0), Some((0, "foo")), None)),
+                    ),
+                    (
+                        3,
+                        entry(
+                            (Some(0), Some((0, "foo")), None),
+                            (Some(0), Some((0, "foo")), Some((0, "foo"))),
+                        ),
+                    ),
+                ],
+            ),
+            (
+                "1".to_owned(),
+                vec![(
+                    1,
+                    entry((Some(0), None, None), (Some(1), Some((1, "foo")), None)),
+                )],
+            ),
+        ];
+        assert!(verify(&logs).is_empty());
+    }
+
+    #[test]
+    fn broken_promise() {
+        let logs = vec![(
+            "0".to_owned(),
+            vec![(
+                1,
+                entry((Some(2), None, None), (Some(2), Some((1, "foo")), None)),
+            )],
+        )];
+        assert_eq!(verify(&logs).len(), 1);
+    }
+
+    #[test]
+    fn unrecorded_change() {
+        let logs = vec![(
+            "0".to_owned(),
+            vec![
+                (1, entry((None, None, None), (Some(0), None, None))),
+                (2, entry((Some(1), None, None), (Some(1), None, None))),
+            ],
+        )];
+        assert_eq!(verify(&logs).len(), 1);
+    }
+
+    #[test]
+    fn disagreement() {
+        let logs = vec![
+            (
+                "0".to_owned(),
+                vec![(
+                    1,
+                    entry(
+                        (Some(0), Some((0, "foo")), None),
+                        (Some(0), Some((0, "foo")), Some((0, "foo"))),
+                    ),
+                )],
+            ),
+            (
+                "1".to_owned(),
+                vec![(
+                    1,
+                    entry((Some(0), None, None), (Some(1), Some((1, "bar")), None)),
+                )],
+            ),
+        ];
+        assert_eq!(verify(&logs).len(), 1);
+    }
+
+    #[test]
+    fn instances_checked_separately() {
+        let in_instance = |instance: &str, entry: Entry| Entry {
+            instance: Some(instance.to_owned()),
+            ..entry
+        };
+        let logs = vec![
+            (
+                "0".to_owned(),
+                vec![
+                    (
+                        1,
+                        in_instance(
+                            "a",
+                            entry(
+                                (Some(0), Some((0, "foo")), None),
+                                (Some(0), Some((0, "foo")), Some((0, "foo"))),
+                            ),
+                        ),
+                    ),
+                    (
+                        2,
+                        in_instance("b", entry((None, None, None), (Some(3), None, None))),
+                    ),
+                ],
+            ),
+            (
+                "1".to_owned(),
+                vec![(
+                    1,
+                    in_instance(
+                        "b",
+                        entry((Some(0), None, None), (Some(1), Some((1, "bar")), None)),
+                    ),
+                )],
+            ),
+        ];
+        assert!(verify(&logs).is_empty());
+    }
+
+    #[tokio::test]
+    async fn entries_are_recorded_in_turn_order() {
+        let data_dir = TestDir::new("audit");
+        let path = data_dir.path().join("audit.log");
+        let audit_log = AuditLog::open(Some(&path)).await.unwrap();
+
+        // The second entry is ready first, but it waits for the first one. A turn which is dropped
+        // doesn't hold up the ones after it.
+        let first = audit_log.turn();
+        drop(audit_log.turn());
+        let second = audit_log.turn();
+        let second = tokio::spawn({
+            let audit_log = audit_log.clone();
+            async move {
+                audit_log
+                    .record(second, None, None, "/accept", &2_u8, initial(), initial())
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+        audit_log
+            .record(first, None, None, "/prepare", &1_u8, initial(), initial())
+            .await
+            .unwrap();
+        second.await.unwrap().unwrap();
+
+        let endpoints = read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Entry>(line).unwrap().endpoint)
+            .collect::<Vec<_>>();
+        assert_eq!(endpoints, vec!["/prepare", "/accept"]);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{address::Address, peers::Peers, shutdown::Shutdown, state::ProposalNumber};
+use rand::RngExt;
+use serde::Serialize;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::time::sleep;
+
+// How long proposers wait before trying again
+#[derive(Clone, Copy, Debug)]
+pub struct Delays {
+    // The most a proposer waits after losing its first round. This doubles with every round it loses
+    // in a row, up to the maximum.
+    pub initial: Duration,
+    pub max: Duration,
+
+    // How long to wait between attempts to open a fast ballot or to send a value in one
+    pub fast: Duration,
+}
+
+// How much the proposers on this node have had to contend with each other and with other nodes
+#[derive(Serialize)]
+pub struct Contention {
+    pub rounds: u64,
+    pub rounds_lost: u64,
+    pub yields: u64,
+    pub backoff_seconds: f64,
+}
+
+// Counters behind the contention metrics
+#[derive(Default)]
+struct Counters {
+    rounds: AtomicU64,
+    rounds_lost: AtomicU64,
+    yields: AtomicU64,
+    backoff_ms: AtomicU64,
+}
+
+// The timing of every proposer on the node and the health of the nodes they send requests to, along
+// with their contention metrics
+#[derive(Clone)]
+pub struct Backoff {
+    delays: Delays,
+    peers: Peers,
+    counters: Arc<Counters>,
+}
+
+impl Backoff {
+    pub fn new(delays: Delays, peers: Peers) -> Self {
+        Self {
+            delays,
+            peers,
+            counters: Arc::default(),
+        }
+    }
+
+    // How long to wait between attempts in a fast ballot
+    pub fn fast_delay(&self) -> Duration {
+        self.delays.fast
+    }
+
+    // The health of the nodes
+    pub fn peers(&self) -> &Peers {
+        &self.peers
+    }
+
+    // Start keeping track of the rounds of a proposer on the node with the given address.
+    pub fn rounds<'a>(&'a self, address: &'a Address) -> Rounds<'a> {
+        Rounds {
+            backoff: self,
+            address,
+            consecutive_losses: 0,
+        }
+    }
+
+    // Report the contention metrics.
+    pub fn contention(&self) -> Contention {
+        // The `as` conversion is fine since precision is only lost after hundreds of thousands of
+        // years of backing off.
+        #[allow(clippy::cast_precision_loss)]
+        let backoff_seconds = self.counters.backoff_ms.load(Ordering::Relaxed) as f64 / 1_000.0_f64;
+        Contention {
+            rounds: self.counters.rounds.load(Ordering::Relaxed),
+            rounds_lost: self.counters.rounds_lost.load(Ordering::Relaxed),
+            yields: self.counters.yields.load(Ordering::Relaxed),
+            backoff_seconds,
+        }
+    }
+}
+
+// The rounds run by one proposer
+pub struct Rounds<'a> {
+    backoff: &'a Backoff,
+    address: &'a Address,
+    consecutive_losses: u32,
+}
+
+impl Rounds<'_> {
+    // Record the start of a round.
+    pub fn start(&self) {
+        self.backoff.counters.rounds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record the loss of a round to the proposer at the given address, if known, and wait before the
+    // next one. Returns nothing if a shutdown was requested first.
+    pub async fn lose(&mut self, winner: Option<&Address>, shutdown: &Shutdown) -> Option<()> {
+        self.consecutive_losses = self.consecutive_losses.saturating_add(1);
+        let yielding = winner.is_some_and(|winner| winner > self.address);
+        let delay = delay(
+            &self.backoff.delays,
+            self.consecutive_losses,
+            yielding,
+            rand::rng().random::<f64>(),
+        );
+
+        let counters = &self.backoff.counters;
+        counters.rounds_lost.fetch_add(1, Ordering::Relaxed);
+        if yielding {
+            counters.yields.fetch_add(1, Ordering::Relaxed);
+        }
+        counters.backoff_ms.fetch_add(
+            u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        debug!(
+            consecutive_losses = self.consecutive_losses,
+            yielding = yielding,
+            delay_seconds = delay.as_secs_f64();
+            "Backing off.",
+        );
+
+        shutdown.interrupt(sleep(delay)).await
+    }
+}
+
+// Determine which proposer beat a round with the given proposal number, given the minimum proposal
+// numbers the nodes responded with, so the loser can yield to it if it's ranked higher.
+pub fn winner<'a>(
+    proposal_number: &ProposalNumber,
+    min_proposal_numbers: impl IntoIterator<Item = &'a ProposalNumber>,
+) -> Option<Address> {
+    min_proposal_numbers
+        .into_iter()
+        .filter(|min_proposal_number| *min_proposal_number > proposal_number)
+        .max()
+        .map(|min_proposal_number| min_proposal_number.proposer_address.clone())
+}
+
+// Determine how long to wait after losing some number of rounds in a row, given a random number in
+// [0, 1). The delay is random so competing proposers are unlikely to collide again, and its range
+// doubles with every loss so they collide less and less often. Proposers ranked below the one which
+// beat them (i.e., which lose ties to it) wait in the upper half of the range and the others in the
+// lower half, so the proposers which are ranked higher tend to finish first.
+fn delay(delays: &Delays, consecutive_losses: u32, yielding: bool, random: f64) -> Duration {
+    let ceiling = delays
+        .initial
+        .saturating_mul(
+            1_u32
+                .checked_shl(consecutive_losses.saturating_sub(1))
+                .unwrap_or(u32::MAX),
+        )
+        .min(delays.max);
+    let half = ceiling / 2;
+    if yielding {
+        half + half.mul_f64(random)
+    } else {
+        half.mul_f64(random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backoff::{Delays, delay};
+    use std::time::Duration;
+
+    const DELAYS: Delays = Delays {
+        initial: Duration::from_millis(100),
+        max: Duration::from_secs(1),
+        fast: Duration::from_millis(200),
+    };
+
+    #[test]
+    fn delay_doubles_up_to_maximum() {
+        assert_eq!(
+            delay(&DELAYS, 1, true, 0.999_f64),
+            Duration::from_micros(99_950)
+        );
+        assert_eq!(delay(&DELAYS, 2, true, 0.0_f64), Duration::from_millis(100));
+        assert_eq!(delay(&DELAYS, 3, true, 0.0_f64), Duration::from_millis(200));
+        assert_eq!(delay(&DELAYS, 4, true, 0.0_f64), Duration::from_millis(400));
+        assert_eq!(delay(&DELAYS, 5, true, 0.0_f64), Duration::from_millis(500));
+        assert_eq!(
+            delay(&DELAYS, 100, true, 0.0_f64),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn yielding_waits_longer() {
+        for random in [0.0_f64, 0.5_f64, 0.999_f64] {
+            assert!(delay(&DELAYS, 3, false, random) <= delay(&DELAYS, 3, true, 0.0_f64));
+        }
+    }
+}
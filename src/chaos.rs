@@ -0,0 +1,512 @@
+use crate::{
+    address::Address,
+    rpc::{HttpClient, SENDER_HEADER, new_client},
+    transport::{Connector, Listener, uri},
+};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    HeaderMap, Method, Request, Response, body::Incoming, header::HOST, service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use rand::{RngExt, SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::Notify,
+    time::{sleep, timeout},
+};
+
+// How long a request which is being reordered waits for another one to go ahead of it
+const MAX_REORDER_HOLD: Duration = Duration::from_secs(1);
+
+// The faults to inject into the messages passing through the proxy
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    // The seed for the random choices of which messages to disrupt, so a run can be repeated. A
+    // random one is used if none is given here or on the command line.
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+// A rule for disrupting messages. Each message is disrupted according to the first rule which
+// matches it, if any.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    // The messages the rule applies to: the ones sent to paths ending with this endpoint (so
+    // `/accept` also matches the endpoint of every named instance), by these nodes, from this many
+    // milliseconds after the proxy starts until this many. Criteria which are left out match every
+    // message.
+    pub endpoint: Option<String>,
+    pub senders: Option<Vec<Address>>,
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+
+    // Whether to drop every message the rule applies to, cutting the senders off from the node
+    pub partition: bool,
+
+    // The probabilities of dropping a request before it's delivered, dropping the response after the
+    // request is delivered, delivering a request twice, and holding a request back until another one
+    // is done with
+    pub drop: f64,
+    pub drop_response: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+
+    // The range of the random delay before a request is delivered
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Rule {
+    // Determine whether the rule applies to a message.
+    fn matches(&self, path: &str, sender: Option<&Address>, elapsed: Duration) -> bool {
+        let elapsed_ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        self.endpoint
+            .as_ref()
+            .is_none_or(|endpoint| path.ends_with(endpoint.as_str()))
+            && self
+                .senders
+                .as_ref()
+                .is_none_or(|senders| sender.is_some_and(|sender| senders.contains(sender)))
+            && self.start_ms.is_none_or(|start_ms| elapsed_ms >= start_ms)
+            && self.end_ms.is_none_or(|end_ms| elapsed_ms < end_ms)
+    }
+
+    // Check that the probabilities are probabilities and the delays form a range.
+    fn validate(&self) -> Result<(), String> {
+        for (name, probability) in [
+            ("drop", self.drop),
+            ("drop_response", self.drop_response),
+            ("duplicate", self.duplicate),
+            ("reorder", self.reorder),
+        ] {
+            if !(0.0_f64..=1.0_f64).contains(&probability) {
+                return Err(format!("`{name}` must be between 0 and 1."));
+            }
+        }
+
+        if self.min_delay_ms > self.max_delay_ms {
+            return Err("`min_delay_ms` must not exceed `max_delay_ms`.".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+// What happens to a message
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Fate {
+    drop: bool,
+    drop_response: bool,
+    duplicate: bool,
+    reorder: bool,
+    delay: Duration,
+}
+
+impl Script {
+    // Decide what happens to a message.
+    fn fate(
+        &self,
+        path: &str,
+        sender: Option<&Address>,
+        elapsed: Duration,
+        rng: &mut StdRng,
+    ) -> Fate {
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(path, sender, elapsed))
+        else {
+            return Fate::default();
+        };
+
+        Fate {
+            drop: rule.partition || rng.random::<f64>() < rule.drop,
+            drop_response: rng.random::<f64>() < rule.drop_response,
+            duplicate: rng.random::<f64>() < rule.duplicate,
+            reorder: rng.random::<f64>() < rule.reorder,
+            delay: Duration::from_millis(rng.random_range(rule.min_delay_ms..=rule.max_delay_ms)),
+        }
+    }
+}
+
+// Read a script from a file.
+pub async fn read_script(path: &Path) -> io::Result<Script> {
+    // Read the file into a buffer.
+    let mut file = File::open(path).await?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).await?;
+
+    // Deserialize and validate the data.
+    yaml_serde::from_slice(&contents)
+        .map_err(|error| error.to_string())
+        .and_then(|script: Script| {
+            for rule in &script.rules {
+                rule.validate()?;
+            }
+            Ok(script)
+        })
+        .map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Error loading chaos script `{}`. Reason: {}",
+                    path.to_string_lossy(),
+                    error,
+                ),
+            )
+        })
+}
+
+// A proxy in front of a node which disrupts the messages sent to it
+struct Proxy {
+    target: Address,
+    script: Script,
+    client: HttpClient,
+    rng: Mutex<StdRng>,
+    started_at: Instant,
+
+    // Notified whenever the proxy is done with a request, whether it was delivered or dropped, so
+    // requests being reordered can go after it
+    settled: Notify,
+}
+
+// Notifies the proxy when it's done with a request, however that happens (including if the sender
+// hangs up)
+struct Settled<'a>(&'a Notify);
+
+impl Drop for Settled<'_> {
+    fn drop(&mut self) {
+        self.0.notify_waiters();
+    }
+}
+
+// A request to deliver to the node
+#[derive(Clone)]
+struct Message {
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+// Listen on one address and forward the requests to a node at another, disrupting them according
+// to the script. This only returns if something goes wrong.
+pub async fn run(
+    listen: &Address,
+    target: &Address,
+    script: Script,
+    seed: Option<u64>,
+) -> io::Result<()> {
+    let seed = seed.or(script.seed).unwrap_or_else(rand::random);
+    let proxy = Arc::new(Proxy {
+        target: target.clone(),
+        script,
+        client: new_client(Connector::default(), false),
+        rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        started_at: Instant::now(),
+        settled: Notify::new(),
+    });
+
+    let listener = Listener::bind(listen).await.map_err(|error| {
+        io::Error::other(format!("Unable to bind to {listen}. Reason: {error}"))
+    })?;
+    info!(seed = seed; "Forwarding requests from {listen} to {target}.");
+
+    loop {
+        let stream = listener.accept().await.map_err(|error| {
+            io::Error::other(format!("Unable to accept connection. Reason: {error}"))
+        })?;
+        let proxy = proxy.clone();
+
+        tokio::spawn(async move {
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder.http1().timer(TokioTimer::new());
+            builder.http2().timer(TokioTimer::new());
+            let connection = builder.serve_connection(
+                TokioIo::new(stream),
+                service_fn(move |request| handle_request(proxy.clone(), request)),
+            );
+
+            // Dropping a message closes the connection it came in on, so errors are expected.
+            if let Err(error) = connection.await {
+                debug!("Connection closed. Reason: {error}");
+            }
+        });
+    }
+}
+
+// Decide what happens to a request, and carry it out. Dropped messages fail the request without a
+// response, as if the connection was lost.
+async fn handle_request(
+    proxy: Arc<Proxy>,
+    request: Request<Incoming>,
+) -> io::Result<Response<Full<Bytes>>> {
+    let _settled = Settled(&proxy.settled);
+    let sender = request
+        .headers()
+        .get(SENDER_HEADER)
+        .and_then(|sender| sender.to_str().ok())
+        .and_then(|sender| sender.parse::<Address>().ok());
+    let fate = {
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        let mut rng = proxy.rng.lock().unwrap();
+        proxy.script.fate(
+            request.uri().path(),
+            sender.as_ref(),
+            proxy.started_at.elapsed(),
+            &mut rng,
+        )
+    };
+    let sender = sender.map_or_else(|| "unknown".to_owned(), |sender| sender.to_string());
+    let endpoint = request.uri().path().to_owned();
+
+    if fate.drop {
+        info!(endpoint = endpoint, sender = sender; "Dropping request.");
+        return Err(io::Error::other("The request was dropped."));
+    }
+
+    // Read the whole request before delaying it, as the node would.
+    let (parts, body) = request.into_parts();
+    let message = Message {
+        method: parts.method,
+        path: parts
+            .uri
+            .path_and_query()
+            .map_or_else(|| endpoint.clone(), ToString::to_string),
+        headers: parts.headers,
+        body: body
+            .collect()
+            .await
+            .map_err(|error| {
+                io::Error::other(format!("Unable to read request body. Reason: {error}"))
+            })?
+            .to_bytes(),
+    };
+
+    if !fate.delay.is_zero() {
+        debug!(endpoint = endpoint, sender = sender; "Delaying request by {:?}.", fate.delay);
+        sleep(fate.delay).await;
+    }
+
+    if fate.reorder {
+        info!(endpoint = endpoint, sender = sender; "Holding request back.");
+        let _ = timeout(MAX_REORDER_HOLD, proxy.settled.notified()).await;
+    }
+
+    if fate.duplicate {
+        info!(endpoint = endpoint, sender = sender; "Duplicating request.");
+        let proxy = proxy.clone();
+        let message = message.clone();
+        tokio::spawn(async move {
+            if let Err(error) = deliver(&proxy, message).await {
+                debug!("Unable to deliver duplicate request. Reason: {error}");
+            }
+        });
+    }
+
+    let response = deliver(&proxy, message).await?;
+
+    if fate.drop_response {
+        info!(endpoint = endpoint, sender = sender; "Dropping response.");
+        return Err(io::Error::other("The response was dropped."));
+    }
+
+    Ok(response)
+}
+
+// Send a request to the node, and read the whole response.
+async fn deliver(proxy: &Proxy, message: Message) -> io::Result<Response<Full<Bytes>>> {
+    let mut request = Request::builder()
+        .method(message.method)
+        .uri(uri(&proxy.target, &message.path));
+    for (name, value) in &message.headers {
+        // The client fills in the host of the node.
+        if name != HOST {
+            request = request.header(name, value);
+        }
+    }
+
+    // The `unwrap` is safe since the parts came from a well-formed request.
+    let response = proxy
+        .client
+        .request(request.body(Full::new(message.body)).unwrap())
+        .await
+        .map_err(|error| io::Error::other(format!("Unable to deliver request. Reason: {error}")))?;
+
+    let (parts, body) = response.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|error| {
+            io::Error::other(format!("Unable to read response body. Reason: {error}"))
+        })?
+        .to_bytes();
+    Ok(Response::from_parts(parts, Full::new(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        chaos::{Fate, Rule, Script},
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    fn node(port: u16) -> Address {
+        Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+    }
+
+    #[test]
+    fn parse_script() {
+        let script = r#"
+seed: 42
+rules:
+  - endpoint: /accept
+    senders: ["127.0.0.1:3001"]
+    start_ms: 1000
+    end_ms: 5000
+    partition: true
+  - drop: 0.25
+    min_delay_ms: 10
+    max_delay_ms: 50
+    "#
+        .trim();
+
+        let result = Script {
+            seed: Some(42),
+            rules: vec![
+                Rule {
+                    endpoint: Some("/accept".to_owned()),
+                    senders: Some(vec![node(3001)]),
+                    start_ms: Some(1000),
+                    end_ms: Some(5000),
+                    partition: true,
+                    ..Rule::default()
+                },
+                Rule {
+                    drop: 0.25_f64,
+                    min_delay_ms: 10,
+                    max_delay_ms: 50,
+                    ..Rule::default()
+                },
+            ],
+        };
+
+        assert_eq!(yaml_serde::from_str::<Script>(script).unwrap(), result);
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let script = Script {
+            seed: None,
+            rules: vec![
+                Rule {
+                    endpoint: Some("/prepare".to_owned()),
+                    senders: Some(vec![node(3001)]),
+                    end_ms: Some(1000),
+                    partition: true,
+                    ..Rule::default()
+                },
+                Rule {
+                    duplicate: 1.0_f64,
+                    min_delay_ms: 5,
+                    max_delay_ms: 5,
+                    ..Rule::default()
+                },
+            ],
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let duplicated = Fate {
+            duplicate: true,
+            delay: Duration::from_millis(5),
+            ..Fate::default()
+        };
+
+        // The partition applies to the endpoints of named instances too.
+        let fate = script.fate(
+            "/instances/foo/prepare",
+            Some(&node(3001)),
+            Duration::ZERO,
+            &mut rng,
+        );
+        assert!(fate.drop);
+
+        // Other senders, other endpoints, and later messages fall through to the next rule.
+        for (path, sender, elapsed) in [
+            ("/prepare", Some(node(3002)), Duration::ZERO),
+            ("/prepare", None, Duration::ZERO),
+            ("/accept", Some(node(3001)), Duration::ZERO),
+            ("/prepare", Some(node(3001)), Duration::from_secs(1)),
+        ] {
+            assert_eq!(
+                script.fate(path, sender.as_ref(), elapsed, &mut rng),
+                duplicated,
+            );
+        }
+    }
+
+    #[test]
+    fn fates_are_repeatable() {
+        let script = Script {
+            seed: None,
+            rules: vec![Rule {
+                drop: 0.5_f64,
+                reorder: 0.5_f64,
+                max_delay_ms: 100,
+                ..Rule::default()
+            }],
+        };
+        let fates = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20_u32)
+                .map(|_| script.fate("/accept", None, Duration::ZERO, &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fates(7), fates(7));
+        assert_ne!(fates(7), fates(8));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(
+            Rule {
+                drop: 1.5_f64,
+                ..Rule::default()
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(
+            Rule {
+                min_delay_ms: 10,
+                max_delay_ms: 5,
+                ..Rule::default()
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(Rule::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{
+    Settings,
+    acceptor::STATE_ENDPOINT,
+    address::Address,
+    backoff, peers,
+    rpc::{self, HttpClient, new_client},
+    run,
+    shutdown::Shutdown,
+    state::ProposalNumber,
+    test_dir::TestDir,
+    transport::{Connector, Listener},
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+// How long to wait for a node to learn the chosen value
+const LEARN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often to ask a node whether it has learned the chosen value
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// A node which is running
+struct Node {
+    shutdown: Shutdown,
+    task: JoinHandle<io::Result<Option<(ProposalNumber, String)>>>,
+}
+
+// A cluster of nodes running in this process, so tests can start, stop, kill, and restart nodes and
+// check what they learn. The nodes listen on ephemeral ports and keep their data in a temporary
+// directory. They keep their data when they're stopped or killed, so they pick up where they left off
+// when they're started again.
+pub struct Cluster {
+    data_dir: TestDir,
+    nodes: Vec<Address>,
+    client: HttpClient,
+
+    // The listeners bound for the nodes which haven't been started yet
+    listeners: Vec<Option<Listener>>,
+
+    running: Vec<Option<Node>>,
+}
+
+impl Cluster {
+    // Set up a cluster of the given size, without starting any of the nodes. The ports are bound
+    // right away, and each node gets its listener when it's first started, so no other test can take
+    // a port in between.
+    pub async fn new(size: usize) -> Self {
+        let mut nodes = vec![];
+        let mut listeners = vec![];
+        for _ in 0..size {
+            let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+                .await
+                .unwrap();
+            nodes.push(Address::Tcp(listener.local_addr().unwrap()));
+            listeners.push(Some(Listener::Tcp(listener)));
+        }
+
+        Self {
+            data_dir: TestDir::new("cluster"),
+            nodes,
+            client: new_client(Connector::default(), false),
+            listeners,
+            running: (0..size).map(|_| None).collect(),
+        }
+    }
+
+    // The settings of a node. Delays are kept short so the tests run quickly, and nodes stop without
+    // a grace period, so stopping a node is nearly as abrupt as killing it.
+    fn settings(&self, node_index: usize, proposal: Option<&str>) -> Settings {
+        let address = self.nodes[node_index].clone();
+        Settings {
+            nodes: self.nodes.clone(),
+            node_index,
+            data_file_path: self
+                .data_dir
+                .path()
+                .join(address.to_string().replace(':', "-")),
+            address,
+            proposal: proposal.map(ToOwned::to_owned),
+            fast: false,
+            register: false,
+            leader_lease: None,
+            instance: None,
+            shutdown_grace_period: Duration::ZERO,
+            max_clock_skew: Duration::from_millis(500),
+            retry_delays: backoff::Delays {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(100),
+                fast: Duration::from_millis(20),
+            },
+            peer_policy: peers::Policy {
+                http2: false,
+                straggler_linger: Duration::from_millis(100),
+                retry_budget: 10,
+                probe_interval: Duration::from_millis(100),
+            },
+            proposer_loop_delay: Duration::from_millis(50),
+            exit_after_chosen: false,
+            linger: Duration::ZERO,
+            result_file: None,
+            trace_file: None,
+            otlp_endpoint: None,
+            audit_log: None,
+        }
+    }
+
+    // Start a node, which proposes the given value, if any. When a node is started again, it binds
+    // its port again, just like a real node which restarts.
+    pub fn start(&mut self, node_index: usize, proposal: Option<&str>) {
+        assert!(
+            self.running[node_index].is_none(),
+            "Node {node_index} is already running.",
+        );
+
+        let settings = self.settings(node_index, proposal);
+        let listener = self.listeners[node_index].take();
+        let shutdown = Shutdown::new();
+        let node_shutdown = shutdown.clone();
+        self.running[node_index] = Some(Node {
+            shutdown,
+            task: tokio::spawn(async move { run(&settings, listener, &node_shutdown).await }),
+        });
+    }
+
+    // Stop a node, returning the chosen proposal if its proposer learned it.
+    pub async fn stop(&mut self, node_index: usize) -> Option<(ProposalNumber, String)> {
+        let node = self.running[node_index]
+            .take()
+            .unwrap_or_else(|| panic!("Node {node_index} isn't running."));
+        node.shutdown.request();
+        node.task.await.unwrap().unwrap()
+    }
+
+    // Kill a node, which stops it as abruptly as a crash. It's not told to shut down, so it doesn't
+    // finish the requests it's handling or anything else it's doing.
+    pub async fn kill(&mut self, node_index: usize) {
+        let node = self.running[node_index]
+            .take()
+            .unwrap_or_else(|| panic!("Node {node_index} isn't running."));
+        node.task.abort();
+        assert!(node.task.await.unwrap_err().is_cancelled());
+    }
+
+    // Stop a node and start it again, proposing the given value, if any.
+    pub async fn restart(&mut self, node_index: usize, proposal: Option<&str>) {
+        self.stop(node_index).await;
+        self.start(node_index, proposal);
+    }
+
+    // Wait for a node to learn the chosen value, and return it.
+    pub async fn chosen_value(&self, node_index: usize) -> String {
+        timeout(LEARN_TIMEOUT, async {
+            loop {
+                if let Ok(state) = rpc::get::<serde_json::Value>(
+                    &self.client,
+                    &self.nodes[node_index],
+                    STATE_ENDPOINT,
+                )
+                .await
+                    && let Some(value) = state["volatile"]["chosen_proposal"][1].as_str()
+                {
+                    return value.to_owned();
+                }
+
+                sleep(POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Node {node_index} didn't learn the chosen value in time."))
+    }
+
+    // Stop every node which is running and delete the data of the cluster.
+    pub async fn shut_down(mut self) {
+        for node_index in 0..self.nodes.len() {
+            if self.running[node_index].is_some() {
+                self.stop(node_index).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::Cluster;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn nodes_agree_on_chosen_value() {
+        let mut cluster = Cluster::new(3).await;
+        cluster.start(0, Some("foo"));
+        cluster.start(1, Some("bar"));
+        cluster.start(2, None);
+
+        let value = cluster.chosen_value(0).await;
+        assert!(value == "foo" || value == "bar");
+        assert_eq!(cluster.chosen_value(1).await, value);
+        assert_eq!(cluster.chosen_value(2).await, value);
+
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test]
+    async fn minority_can_be_down() {
+        let mut cluster = Cluster::new(3).await;
+        cluster.start(0, Some("foo"));
+        cluster.start(1, None);
+        assert_eq!(cluster.chosen_value(1).await, "foo");
+
+        // The node which was down learns the value once it starts, even if it proposes another one.
+        cluster.start(2, Some("bar"));
+        assert_eq!(cluster.chosen_value(2).await, "foo");
+
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test]
+    async fn chosen_value_survives_restarts() {
+        let mut cluster = Cluster::new(3).await;
+        for node_index in 0..3 {
+            cluster.start(node_index, Some("foo"));
+        }
+        assert_eq!(cluster.chosen_value(0).await, "foo");
+
+        // Kill every node and start it again, each proposing something else. The value which was
+        // chosen before is remembered by the acceptors, and none of the new values can be chosen.
+        for node_index in 0..3 {
+            cluster.kill(node_index).await;
+        }
+        for (node_index, value) in ["bar", "baz", "qux"].into_iter().enumerate() {
+            cluster.start(node_index, Some(value));
+        }
+        for node_index in 0..3 {
+            assert_eq!(cluster.chosen_value(node_index).await, "foo");
+        }
+
+        // Restart the nodes one at a time.
+        for node_index in 0..3 {
+            cluster.restart(node_index, Some("quux")).await;
+            assert_eq!(cluster.chosen_value(node_index).await, "foo");
+        }
+
+        cluster.shut_down().await;
+    }
+
+    #[tokio::test]
+    async fn nodes_agree_despite_crashes() {
+        let mut cluster = Cluster::new(3).await;
+        for (node_index, value) in ["foo", "bar", "baz"].into_iter().enumerate() {
+            cluster.start(node_index, Some(value));
+        }
+
+        // Kill each node in turn while the proposers may still be competing, and start it again with
+        // another value.
+        for node_index in 0..3 {
+            sleep(Duration::from_millis(10)).await;
+            cluster.kill(node_index).await;
+            cluster.start(node_index, Some("qux"));
+        }
+
+        let value = cluster.chosen_value(0).await;
+        assert!(["foo", "bar", "baz", "qux"].contains(&value.as_str()));
+        assert_eq!(cluster.chosen_value(1).await, value);
+        assert_eq!(cluster.chosen_value(2).await, value);
+
+        cluster.shut_down().await;
+    }
+}
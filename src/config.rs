@@ -1,12 +1,27 @@
+use crate::address::Address;
 use serde::{Deserialize, Serialize};
-use std::{io, net::SocketAddr, path::Path};
+use std::{io, path::Path};
 use tokio::{fs::File, io::AsyncReadExt};
 
 // A program configuration
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
-    pub nodes: Vec<SocketAddr>,
+    pub nodes: Vec<Address>,
+
+    // Leader leases for the register, if the cluster uses them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader_lease: Option<LeaderLease>,
+}
+
+// The settings for leader leases. The nodes which grant a lease measure it with their own clocks,
+// and the node which holds it measures it with its own, so the holder needs to know how much faster
+// its clock might run than theirs, in parts per million.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeaderLease {
+    pub duration_ms: u64,
+    pub max_clock_drift_ppm: u64,
 }
 
 // Read the config from a file.
@@ -31,8 +46,14 @@ pub async fn read(path: &Path) -> io::Result<Config> {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::Config;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::{
+        address::Address,
+        config::{Config, LeaderLease},
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::PathBuf,
+    };
 
     #[test]
     fn parse_empty() {
@@ -41,7 +62,10 @@ nodes: []
     "
         .trim();
 
-        let result = Config { nodes: vec![] };
+        let result = Config {
+            nodes: vec![],
+            leader_lease: None,
+        };
 
         assert_eq!(yaml_serde::from_str::<Config>(config).unwrap(), result);
     }
@@ -55,7 +79,11 @@ nodes:
         .trim();
 
         let result = Config {
-            nodes: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)],
+            nodes: vec![Address::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                3000,
+            ))],
+            leader_lease: None,
         };
 
         assert_eq!(yaml_serde::from_str::<Config>(config).unwrap(), result);
@@ -73,12 +101,62 @@ nodes:
 
         let result = Config {
             nodes: vec![
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)), 3000),
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)), 3001),
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3)), 3002),
+                Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+                    3000,
+                )),
+                Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+                    3001,
+                )),
+                Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3)),
+                    3002,
+                )),
             ],
+            leader_lease: None,
         };
 
         assert_eq!(yaml_serde::from_str::<Config>(config).unwrap(), result);
     }
+
+    #[test]
+    fn parse_unix() {
+        let config = r#"
+nodes:
+  - "unix:/tmp/paxos-0.sock"
+  - "unix:/tmp/paxos-1.sock"
+    "#
+        .trim();
+
+        let result = Config {
+            nodes: vec![
+                Address::Unix(PathBuf::from("/tmp/paxos-0.sock")),
+                Address::Unix(PathBuf::from("/tmp/paxos-1.sock")),
+            ],
+            leader_lease: None,
+        };
+
+        assert_eq!(yaml_serde::from_str::<Config>(config).unwrap(), result);
+    }
+
+    #[test]
+    fn parse_leader_lease() {
+        let config = r#"
+nodes:
+  - "127.0.0.1:3000"
+leader_lease:
+  duration_ms: 2000
+  max_clock_drift_ppm: 500
+    "#
+        .trim();
+
+        assert_eq!(
+            yaml_serde::from_str::<Config>(config).unwrap().leader_lease,
+            Some(LeaderLease {
+                duration_ms: 2000,
+                max_clock_drift_ppm: 500,
+            }),
+        );
+    }
 }
@@ -0,0 +1,198 @@
+use crate::{
+    acceptor::STATE_ENDPOINT,
+    address::Address,
+    rpc::{HttpClient, get},
+    state::{self, ProposalNumber},
+};
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::timeout;
+
+// The dashboard page, which polls the cluster endpoint and renders the result
+pub const PAGE: &str = include_str!("../resources/dashboard.html");
+
+// The endpoint which reports the state of every node to the dashboard
+pub const CLUSTER_ENDPOINT: &str = "/cluster";
+
+// How many events each node remembers for the timeline
+const TIMELINE_CAPACITY: usize = 50;
+
+// Duration constants
+const PEER_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Something notable that happened to the state of an acceptor
+#[derive(Clone, Serialize)]
+pub struct Event {
+    pub time: u64,
+    pub endpoint: &'static str,
+    pub description: String,
+}
+
+// The parts of the state which the timeline reports changes to
+pub struct Snapshot {
+    min_proposal_number: Option<ProposalNumber>,
+    accepted_proposal: Option<(ProposalNumber, String)>,
+    chosen_proposal: Option<(ProposalNumber, String)>,
+}
+
+impl Snapshot {
+    pub fn of(state: &(state::Durable, state::Volatile)) -> Self {
+        Self {
+            min_proposal_number: state.0.min_proposal_number.clone(),
+            accepted_proposal: state.0.accepted_proposal.clone(),
+            chosen_proposal: state.1.chosen_proposal.clone(),
+        }
+    }
+}
+
+// The most recent events on this node, oldest first
+#[derive(Clone, Default)]
+pub struct Timeline {
+    events: Arc<Mutex<VecDeque<Event>>>,
+}
+
+impl Timeline {
+    // Record the changes a request made to the state, if any.
+    pub fn record(&self, endpoint: &'static str, before: &Snapshot, after: &Snapshot) {
+        let mut descriptions = vec![];
+        if after.min_proposal_number != before.min_proposal_number
+            && let Some(proposal_number) = &after.min_proposal_number
+        {
+            descriptions.push(format!(
+                "Promised to ignore proposals below round {} by {}.",
+                proposal_number.round, proposal_number.proposer_address,
+            ));
+        }
+        if after.accepted_proposal != before.accepted_proposal
+            && let Some((proposal_number, value)) = &after.accepted_proposal
+        {
+            descriptions.push(format!(
+                "Accepted `{value}` from round {} by {}.",
+                proposal_number.round, proposal_number.proposer_address,
+            ));
+        }
+        if after.chosen_proposal != before.chosen_proposal
+            && let Some((proposal_number, value)) = &after.chosen_proposal
+        {
+            descriptions.push(format!(
+                "Learned that `{value}` was chosen in round {} by {}.",
+                proposal_number.round, proposal_number.proposer_address,
+            ));
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| {
+                duration.as_millis().try_into().unwrap_or(u64::MAX)
+            });
+
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        let mut events = self.events.lock().unwrap();
+        for description in descriptions {
+            if events.len() == TIMELINE_CAPACITY {
+                events.pop_front();
+            }
+            events.push_back(Event {
+                time,
+                endpoint,
+                description,
+            });
+        }
+    }
+
+    // Return the recorded events.
+    pub fn events(&self) -> Vec<Event> {
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+// What the dashboard knows about a node
+#[derive(Serialize)]
+struct NodeStatus<'a> {
+    index: usize,
+    address: &'a Address,
+    state: Option<Value>,
+    error: Option<String>,
+}
+
+// Ask every node for its state. Nodes which don't respond promptly are reported as unreachable
+// rather than holding up the whole page.
+pub async fn cluster(client: &HttpClient, nodes: &[Address]) -> Value {
+    let statuses = join_all(nodes.iter().enumerate().map(|(index, node)| async move {
+        let result = timeout(PEER_TIMEOUT, get::<Value>(client, node, STATE_ENDPOINT))
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::other("The node did not respond in time.")));
+        match result {
+            Ok(state) => NodeStatus {
+                index,
+                address: node,
+                state: Some(state),
+                error: None,
+            },
+            Err(error) => NodeStatus {
+                index,
+                address: node,
+                state: None,
+                error: Some(error.to_string()),
+            },
+        }
+    }))
+    .await;
+
+    // The `unwrap` is safe because serialization should never fail.
+    serde_json::to_value(statuses).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        dashboard::{Snapshot, TIMELINE_CAPACITY, Timeline},
+        state::{ProposalNumber, initial},
+    };
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn timeline_records_changes() {
+        let timeline = Timeline::default();
+        let mut state = initial();
+        let before = Snapshot::of(&state);
+        let proposal_number = ProposalNumber {
+            round: 0,
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)),
+        };
+        state.0.min_proposal_number = Some(proposal_number.clone());
+        state.0.accepted_proposal = Some((proposal_number, "foo".to_owned()));
+        timeline.record("/accept", &before, &Snapshot::of(&state));
+        assert_eq!(timeline.events().len(), 2);
+
+        let before = Snapshot::of(&state);
+        timeline.record("/accept", &before, &Snapshot::of(&state));
+        assert_eq!(timeline.events().len(), 2);
+    }
+
+    #[test]
+    fn timeline_is_bounded() {
+        let timeline = Timeline::default();
+        let mut state = initial();
+        for round in 0..2 * TIMELINE_CAPACITY {
+            let before = Snapshot::of(&state);
+            state.0.min_proposal_number = Some(ProposalNumber {
+                round: round as u64,
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    3000,
+                )),
+            });
+            timeline.record("/prepare", &before, &Snapshot::of(&state));
+        }
+        assert_eq!(timeline.events().len(), TIMELINE_CAPACITY);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{
+    address::Address,
+    rpc::{HttpClient, get},
+    shutdown::Shutdown,
+};
+use futures::future::join_all;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    fs::{OpenOptions, remove_file},
+    time::timeout,
+};
+
+// Endpoints
+pub const HEALTH_ENDPOINT: &str = "/healthz";
+pub const READINESS_ENDPOINT: &str = "/readyz";
+pub const PING_ENDPOINT: &str = "/ping";
+
+// Duration constants
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Used to give each probe file a unique name, so overlapping health checks don't collide
+static NEXT_PROBE: AtomicU64 = AtomicU64::new(0);
+
+// Check that the node can make progress on its own, which means it can write to its state file.
+pub async fn health(data_file_path: &Path) -> Result<String, String> {
+    check_writable(data_file_path).await.map_err(|error| {
+        format!(
+            "Unable to write to state file `{}`. Reason: {}",
+            data_file_path.to_string_lossy(),
+            error,
+        )
+    })?;
+    Ok("Healthy.".to_owned())
+}
+
+// Check that the node is ready to take part in the protocol. The state file has always been loaded
+// by the time the acceptor starts serving requests, so this only needs to check that the node
+// isn't shutting down and that it can reach a quorum of the cluster (itself included).
+pub async fn readiness(
+    client: &HttpClient,
+    nodes: &[Address],
+    node_index: usize,
+    shutdown: &Shutdown,
+) -> Result<String, String> {
+    if shutdown.is_requested() {
+        return Err("The node is shutting down.".to_owned());
+    }
+
+    let reachable = 1 + join_all(
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != node_index)
+            .map(|(_, node)| ping(client, node)),
+    )
+    .await
+    .into_iter()
+    .filter(|reachable| *reachable)
+    .count();
+
+    let quorum = nodes.len() / 2 + 1;
+    if reachable >= quorum {
+        Ok(format!(
+            "Ready. {reachable} of {} nodes are reachable.",
+            nodes.len()
+        ))
+    } else {
+        Err(format!(
+            "Only {reachable} of {} nodes are reachable, but a quorum is {quorum}.",
+            nodes.len(),
+        ))
+    }
+}
+
+// Determine whether a node responds to a ping in time.
+async fn ping(client: &HttpClient, node: &Address) -> bool {
+    matches!(
+        timeout(PING_TIMEOUT, get::<()>(client, node, PING_ENDPOINT)).await,
+        Ok(Ok(())),
+    )
+}
+
+// Check that a file can be opened for writing without modifying it. If it doesn't exist yet, check
+// that a file can be created next to it instead.
+async fn check_writable(path: &Path) -> io::Result<()> {
+    match OpenOptions::new().write(true).open(path).await {
+        Ok(_) => Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let probe_path = probe_path(path);
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&probe_path)
+                .await?;
+            remove_file(&probe_path).await
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// Determine a fresh path for a file used to check that a file could be created at the given path.
+fn probe_path(path: &Path) -> PathBuf {
+    let mut probe_path = path.as_os_str().to_owned();
+    probe_path.push(format!(
+        ".probe-{}",
+        NEXT_PROBE.fetch_add(1, Ordering::Relaxed),
+    ));
+    PathBuf::from(probe_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{health::check_writable, test_dir::TestDir};
+    use futures::future::join_all;
+    use std::fs::{remove_dir_all, write};
+
+    #[tokio::test]
+    async fn writable_whether_or_not_file_exists() {
+        let data_dir = TestDir::new("health");
+        let data_file_path = data_dir.path().join("127.0.0.1-3000");
+
+        assert!(check_writable(&data_file_path).await.is_ok());
+        assert!(!data_file_path.exists());
+
+        write(&data_file_path, "{}").unwrap();
+        assert!(check_writable(&data_file_path).await.is_ok());
+
+        remove_dir_all(data_dir.path()).unwrap();
+        assert!(check_writable(&data_file_path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn overlapping_probes_dont_collide() {
+        let data_dir = TestDir::new("health-overlap");
+        let data_file_path = data_dir.path().join("127.0.0.1-3000");
+
+        let results = join_all((0..16_u8).map(|_| check_writable(&data_file_path))).await;
+        assert!(results.iter().all(Result::is_ok));
+    }
+}
@@ -0,0 +1,547 @@
+use crate::state::{self, Durable, ProposalNumber, Volatile};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{
+        File, OpenOptions, create_dir_all, read_dir, read_to_string, remove_file, rename,
+        try_exists,
+    },
+    io::AsyncWriteExt,
+    sync::{Mutex, OnceCell, RwLock},
+};
+
+// The namespace of the instances which are used directly or as keys of the key-value store. For
+// example, the prepare endpoint of the instance named `foo` is `/instances/foo/prepare`.
+pub const INSTANCES_NAMESPACE: &str = "instances";
+
+// The maximum length of an instance name
+const MAX_KEY_LENGTH: usize = 200;
+
+// How many instances can stay loaded before the idle ones are evicted
+const MAX_LOADED: usize = 1024;
+
+// The state of an instance
+pub type State = Arc<RwLock<(Durable, Volatile)>>;
+
+// An entry in the chosen log, which is written as a single line of JSON
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ChosenEntry {
+    key: String,
+    proposal: (ProposalNumber, String),
+}
+
+// The chosen log, which records the value chosen for each instance once this node learns it
+struct ChosenLog {
+    path: PathBuf,
+    file: File,
+    proposals: BTreeMap<String, (ProposalNumber, String)>,
+}
+
+// The named instances in a namespace hosted by a node. Each one is an independent single-decree
+// instance of the protocol with its own state file, which is loaded the first time the instance is
+// used. Instances in different namespaces are independent even if they have the same name.
+//
+// Once the node learns the value chosen for an instance, it's recorded in the chosen log. When the
+// node starts, the state files of the instances in the chosen log are deleted, and from then on
+// those instances are compacted: they answer as if they had accepted every proposal numbered at
+// least as high as the chosen one. That's safe since every such proposal has the chosen value. The
+// chosen log is rewritten at the same time, so it has only one entry per instance.
+//
+// Each loaded instance has a cell which is filled in once its state file has been read, so reading
+// one instance's state file doesn't hold up requests for the others. Once `MAX_LOADED` instances
+// are loaded, the ones nobody is using are evicted to make room for more. Their durable state is
+// already in their state files, and the values chosen for them are in the chosen log, so nothing is
+// lost.
+#[derive(Clone)]
+pub struct Instances {
+    namespace: &'static str,
+    directory: PathBuf,
+    loaded: Arc<Mutex<HashMap<String, Arc<OnceCell<State>>>>>,
+    compacted: Arc<BTreeMap<String, (ProposalNumber, String)>>,
+    chosen_log: Arc<Mutex<ChosenLog>>,
+}
+
+impl Instances {
+    // The state files of the instances live in a directory next to the node's own state file, and
+    // so does the chosen log. Opening the instances compacts them as described above.
+    pub async fn open(data_file_path: &Path, namespace: &'static str) -> io::Result<Self> {
+        let mut directory = data_file_path.as_os_str().to_owned();
+        directory.push(format!(".{namespace}"));
+        let directory = PathBuf::from(directory);
+        let mut chosen_log_path = directory.as_os_str().to_owned();
+        chosen_log_path.push(".chosen");
+        let chosen_log_path = PathBuf::from(chosen_log_path);
+
+        let annotate = |action: &str, path: &Path, error: io::Error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to {action} `{}`. Reason: {error}",
+                    path.to_string_lossy(),
+                ),
+            )
+        };
+
+        // Before there were other namespaces, the chosen log of the instances was named after the
+        // node's own state file. If it's still there, move it to where it belongs now.
+        if namespace == INSTANCES_NAMESPACE {
+            let mut legacy_path = data_file_path.as_os_str().to_owned();
+            legacy_path.push(".chosen");
+            let legacy_path = PathBuf::from(legacy_path);
+            if try_exists(&legacy_path)
+                .await
+                .map_err(|error| annotate("read chosen log", &legacy_path, error))?
+                && !try_exists(&chosen_log_path)
+                    .await
+                    .map_err(|error| annotate("read chosen log", &chosen_log_path, error))?
+            {
+                rename(&legacy_path, &chosen_log_path)
+                    .await
+                    .map_err(|error| annotate("move chosen log", &legacy_path, error))?;
+            }
+        }
+
+        // Read the chosen log.
+        let contents = match read_to_string(&chosen_log_path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(annotate("read chosen log", &chosen_log_path, error)),
+        };
+        let mut proposals = BTreeMap::new();
+        for line in contents.lines() {
+            // The last line may be incomplete if the program crashed while writing it, in which case
+            // the value will be learned again.
+            if let Ok(entry) = serde_json::from_str::<ChosenEntry>(line) {
+                proposals.insert(entry.key, entry.proposal);
+            }
+        }
+
+        // Rewrite the chosen log with one entry per instance. The new log replaces the old one
+        // atomically, so a crash in the middle doesn't lose anything. The `unwrap` is safe due to
+        // [ref:data_file_path_has_parent].
+        let parent = data_file_path.parent().unwrap();
+        create_dir_all(parent)
+            .await
+            .map_err(|error| annotate("create directory", parent, error))?;
+        let mut temporary_path = chosen_log_path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        let mut file = File::create(&temporary_path)
+            .await
+            .map_err(|error| annotate("create chosen log", &temporary_path, error))?;
+        for (key, proposal) in &proposals {
+            file.write_all(&chosen_line(key, proposal))
+                .await
+                .map_err(|error| annotate("write chosen log", &temporary_path, error))?;
+        }
+        file.sync_all()
+            .await
+            .map_err(|error| annotate("write chosen log", &temporary_path, error))?;
+        drop(file);
+        rename(&temporary_path, &chosen_log_path)
+            .await
+            .map_err(|error| annotate("replace chosen log", &chosen_log_path, error))?;
+
+        // Delete the state files of the compacted instances.
+        for key in proposals.keys() {
+            let path = directory.join(key);
+            match remove_file(&path).await {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(annotate("delete state file", &path, error)),
+            }
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&chosen_log_path)
+            .await
+            .map_err(|error| annotate("open chosen log", &chosen_log_path, error))?;
+
+        Ok(Self {
+            namespace,
+            directory,
+            loaded: Arc::new(Mutex::new(HashMap::new())),
+            compacted: Arc::new(proposals.clone()),
+            chosen_log: Arc::new(Mutex::new(ChosenLog {
+                path: chosen_log_path,
+                file,
+                proposals,
+            })),
+        })
+    }
+
+    // Determine the path of the state file of an instance. The key must be valid.
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(key)
+    }
+
+    // Determine the name of an instance which is unique across namespaces, like `instances/foo`.
+    pub fn name(&self, key: &str) -> String {
+        format!("{}/{key}", self.namespace)
+    }
+
+    // Determine the prefix of the endpoints of an instance, like `/instances/foo`.
+    pub fn endpoint_prefix(&self, key: &str) -> String {
+        format!("/{}", self.name(key))
+    }
+
+    // Split the path of an endpoint for an instance in this namespace into the name and the
+    // endpoint, if it's one of the given endpoints and the name is valid.
+    pub fn route(&self, path: &str, endpoints: &[&'static str]) -> Option<(String, &'static str)> {
+        let rest = path
+            .strip_prefix('/')?
+            .strip_prefix(self.namespace)?
+            .strip_prefix('/')?;
+        endpoints.iter().find_map(|endpoint| {
+            let key = rest.strip_suffix(endpoint)?;
+            validate_key(key).ok()?;
+            Some((key.to_owned(), *endpoint))
+        })
+    }
+
+    // Get the state of an instance, loading it from its state file if necessary. An instance which
+    // has never been used starts in the initial state. Compacted instances have no state file, so
+    // their state is reconstructed from the chosen proposal, and it must not be persisted. The key
+    // must be valid.
+    pub async fn get(&self, key: &str) -> io::Result<(State, Option<PathBuf>)> {
+        if let Some(proposal) = self.compacted.get(key) {
+            return Ok((
+                Arc::new(RwLock::new((
+                    Durable {
+                        min_proposal_number: Some(proposal.0.clone()),
+                        accepted_proposal: Some(proposal.clone()),
+                        ..state::initial().0
+                    },
+                    Volatile {
+                        chosen_proposal: Some(proposal.clone()),
+                        ..state::initial().1
+                    },
+                ))),
+                None,
+            ));
+        }
+
+        // Find the cell for the instance, making room for it if necessary. The map is only locked
+        // for as long as that takes.
+        let cell = {
+            let mut loaded = self.loaded.lock().await;
+            if !loaded.contains_key(key) && loaded.len() >= MAX_LOADED {
+                evict_idle(&mut loaded);
+            }
+            loaded.entry(key.to_owned()).or_default().clone()
+        };
+
+        // Load the state, unless another request already did. If this fails, the next request for
+        // the instance tries again.
+        let path = self.path(key);
+        let state = cell
+            .get_or_try_init(|| async {
+                let durable = match state::read(&path).await {
+                    Ok(durable) => durable,
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => state::initial().0,
+                    Err(error) => return Err(error),
+                };
+
+                // The instance may have been evicted after its value was chosen.
+                let chosen_proposal = self.chosen_log.lock().await.proposals.get(key).cloned();
+                Ok::<_, io::Error>(Arc::new(RwLock::new((
+                    durable,
+                    Volatile {
+                        chosen_proposal,
+                        ..state::initial().1
+                    },
+                ))))
+            })
+            .await?
+            .clone();
+        Ok((state, Some(path)))
+    }
+
+    // Record the value chosen for an instance in the chosen log, unless it's already there.
+    pub async fn record_chosen(
+        &self,
+        key: &str,
+        proposal: &(ProposalNumber, String),
+    ) -> io::Result<()> {
+        let mut chosen_log = self.chosen_log.lock().await;
+        if chosen_log.proposals.contains_key(key) {
+            return Ok(());
+        }
+
+        let line = chosen_line(key, proposal);
+        let result = async {
+            chosen_log.file.write_all(&line).await?;
+            chosen_log.file.sync_data().await
+        }
+        .await;
+        result.map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to write to chosen log `{}`. Reason: {error}",
+                    chosen_log.path.to_string_lossy(),
+                ),
+            )
+        })?;
+        chosen_log
+            .proposals
+            .insert(key.to_owned(), proposal.clone());
+        Ok(())
+    }
+
+    // List the instances this node knows about, along with the values chosen for them if this node
+    // knows them.
+    pub async fn list(&self) -> io::Result<BTreeMap<String, Option<String>>> {
+        let mut instances = BTreeMap::new();
+
+        // Instances with state files
+        match read_dir(&self.directory).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await? {
+                    if let Some(key) = entry.file_name().to_str()
+                        && validate_key(key).is_ok()
+                    {
+                        instances.insert(key.to_owned(), None);
+                    }
+                }
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+
+        // Instances whose values were learned since they were loaded
+        for (key, state) in self
+            .loaded
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(key, cell)| Some((key, cell.get()?)))
+        {
+            instances.insert(
+                key.clone(),
+                state
+                    .read()
+                    .await
+                    .1
+                    .chosen_proposal
+                    .as_ref()
+                    .map(|(_, value)| value.clone()),
+            );
+        }
+
+        // Instances in the chosen log
+        for (key, (_, value)) in &self.chosen_log.lock().await.proposals {
+            instances.insert(key.clone(), Some(value.clone()));
+        }
+
+        Ok(instances)
+    }
+}
+
+// Evict the loaded instances which nobody is using. Other references to a cell or the state in it
+// are only taken while the map is locked, so an instance referenced only by the map can't be in
+// use, and any write to its state file has finished.
+fn evict_idle(loaded: &mut HashMap<String, Arc<OnceCell<State>>>) {
+    loaded.retain(|_, cell| {
+        Arc::strong_count(cell) > 1 || cell.get().is_some_and(|state| Arc::strong_count(state) > 1)
+    });
+}
+
+// Serialize an entry of the chosen log.
+fn chosen_line(key: &str, proposal: &(ProposalNumber, String)) -> Vec<u8> {
+    // The `unwrap` is safe because serialization should never fail.
+    let mut line = serde_json::to_vec(&ChosenEntry {
+        key: key.to_owned(),
+        proposal: proposal.clone(),
+    })
+    .unwrap();
+    line.push(b'\n');
+    line
+}
+
+// Check that a key can be used as the name of an instance. Keys become file names, so they're
+// restricted to characters which are safe in file names.
+pub fn validate_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(format!(
+            "The name of an instance must have between 1 and {MAX_KEY_LENGTH} characters.",
+        ));
+    }
+
+    if key.starts_with('.')
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!(
+            "`{key}` is not a valid instance name. Names may only contain ASCII letters, digits, \
+                `-`, `_`, and `.`, and they can't start with `.`.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        instances::{INSTANCES_NAMESPACE, Instances, MAX_LOADED, validate_key},
+        state::{self, ProposalNumber},
+        test_dir::TestDir,
+    };
+    use std::{
+        fs::write,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    #[test]
+    fn valid_keys() {
+        assert!(validate_key("job-42").is_ok());
+        assert!(validate_key("a.b_c").is_ok());
+        assert!(validate_key("").is_err());
+        assert!(validate_key(".hidden").is_err());
+        assert!(validate_key("a/b").is_err());
+        assert!(validate_key("..").is_err());
+        assert!(validate_key(&"x".repeat(201)).is_err());
+    }
+
+    #[tokio::test]
+    async fn routes() {
+        let data_dir = TestDir::new("instances-routes");
+        let instances =
+            Instances::open(&data_dir.path().join("127.0.0.1-3000"), INSTANCES_NAMESPACE)
+                .await
+                .unwrap();
+        let path = format!("{}/prepare", instances.endpoint_prefix("job-42"));
+        assert_eq!(path, "/instances/job-42/prepare");
+        assert_eq!(
+            instances.route(&path, &["/accept", "/prepare"]),
+            Some(("job-42".to_owned(), "/prepare")),
+        );
+        assert_eq!(instances.route("/prepare", &["/prepare"]), None);
+        assert_eq!(instances.route("/instances//prepare", &["/prepare"]), None);
+        assert_eq!(
+            instances.route("/instances/a/b/prepare", &["/prepare"]),
+            None
+        );
+        assert_eq!(instances.route("/instances/a/choose", &["/prepare"]), None);
+        assert_eq!(instances.route("/locks/a/prepare", &["/prepare"]), None);
+    }
+
+    #[tokio::test]
+    async fn chosen_instances_are_compacted() {
+        let data_dir = TestDir::new("instances");
+        let data_file_path = data_dir.path().join("127.0.0.1-3000");
+        let proposal = (
+            ProposalNumber {
+                round: 1,
+                proposer_address: Address::Tcp(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    3000,
+                )),
+            },
+            "foo".to_owned(),
+        );
+
+        // Choose a value for one instance, and use another without choosing a value.
+        let instances = Instances::open(&data_file_path, INSTANCES_NAMESPACE)
+            .await
+            .unwrap();
+        for key in ["a", "b"] {
+            let (state, path) = instances.get(key).await.unwrap();
+            let mut guard = state.write().await;
+            guard.0.accepted_proposal = Some(proposal.clone());
+            state::write(&guard.0, &path.unwrap()).await.unwrap();
+        }
+        instances.record_chosen("a", &proposal).await.unwrap();
+        instances.record_chosen("a", &proposal).await.unwrap();
+        assert_eq!(
+            instances
+                .list()
+                .await
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                ("a".to_owned(), Some("foo".to_owned())),
+                ("b".to_owned(), None),
+            ],
+        );
+        drop(instances);
+
+        // The state file of the chosen instance is gone after reopening, but its value isn't.
+        let instances = Instances::open(&data_file_path, INSTANCES_NAMESPACE)
+            .await
+            .unwrap();
+        assert!(!instances.path("a").exists());
+        assert!(instances.path("b").exists());
+        let (state, path) = instances.get("a").await.unwrap();
+        assert!(path.is_none());
+        assert_eq!(state.read().await.1.chosen_proposal, Some(proposal));
+        assert_eq!(instances.list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn idle_instances_are_evicted() {
+        let data_dir = TestDir::new("instances-evict");
+        let instances =
+            Instances::open(&data_dir.path().join("127.0.0.1-3000"), INSTANCES_NAMESPACE)
+                .await
+                .unwrap();
+
+        // An instance which is in use stays loaded.
+        let (held, _) = instances.get("held").await.unwrap();
+        for i in 0..MAX_LOADED {
+            instances.get(&format!("key-{i}")).await.unwrap();
+        }
+        let loaded = instances.loaded.lock().await;
+        assert!(loaded.len() <= MAX_LOADED);
+        assert!(loaded.contains_key("held"));
+        drop(loaded);
+
+        // It's still the same instance.
+        let (state, _) = instances.get("held").await.unwrap();
+        assert!(Arc::ptr_eq(&held, &state));
+    }
+
+    #[tokio::test]
+    async fn legacy_chosen_log_is_migrated() {
+        let data_dir = TestDir::new("instances-legacy");
+        let data_file_path = data_dir.path().join("127.0.0.1-3000");
+        write(
+            data_dir.path().join("127.0.0.1-3000.chosen"),
+            concat!(
+                r#"{"key":"a","proposal":"#,
+                r#"[{"round":1,"proposer_address":"127.0.0.1:3000"},"foo"]}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        let instances = Instances::open(&data_file_path, INSTANCES_NAMESPACE)
+            .await
+            .unwrap();
+        assert!(!data_dir.path().join("127.0.0.1-3000.chosen").exists());
+        let (state, path) = instances.get("a").await.unwrap();
+        assert!(path.is_none());
+        assert_eq!(
+            state
+                .read()
+                .await
+                .1
+                .chosen_proposal
+                .as_ref()
+                .map(|(_, value)| value.as_str()),
+            Some("foo"),
+        );
+    }
+}
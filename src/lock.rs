@@ -0,0 +1,155 @@
+use crate::address::Address;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions, TryLockError, create_dir_all, read},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// A description of the process which owns a data file, recorded in the lock file
+#[derive(Deserialize, Serialize)]
+pub struct Owner {
+    pub pid: u32,
+    pub node_index: usize,
+    pub address: Address,
+    pub version: String,
+    pub started_at: u64,
+}
+
+impl Owner {
+    // Describe the current process.
+    pub fn current(node_index: usize, address: &Address) -> Self {
+        Self {
+            pid: std::process::id(),
+            node_index,
+            address: address.clone(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+        }
+    }
+}
+
+// Determine the path of the lock file which guards a data file.
+fn path(data_file_path: &Path) -> PathBuf {
+    let mut lock_file_path = data_file_path.as_os_str().to_owned();
+    lock_file_path.push(".lock");
+    PathBuf::from(lock_file_path)
+}
+
+// Take an exclusive advisory lock on the lock file for a data file and record the owner in it. The
+// lock is held until the returned file is closed, which the operating system does for us if the
+// process dies. The lock file is deliberately left in place afterward, since deleting it would let
+// another process lock a new file at the same path while a third still holds the old one.
+pub fn acquire(data_file_path: &Path, owner: &Owner) -> io::Result<File> {
+    let lock_file_path = path(data_file_path);
+
+    // The `unwrap` is safe due to [ref:data_file_path_has_parent].
+    create_dir_all(lock_file_path.parent().unwrap())?;
+
+    // Open the file without truncating it, since it might belong to another process.
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_file_path)
+        .map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to open lock file `{}`. Reason: {}",
+                    lock_file_path.to_string_lossy(),
+                    error,
+                ),
+            )
+        })?;
+
+    // Take the lock, or explain who has it.
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "Data file `{}` is in use by another process. Reason: {}",
+                    data_file_path.to_string_lossy(),
+                    describe_owner(&lock_file_path),
+                ),
+            ));
+        }
+        Err(TryLockError::Error(error)) => {
+            // E.g., some network file systems don't support locks.
+            return Err(io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to take the lock on lock file `{}`. Reason: {}",
+                    lock_file_path.to_string_lossy(),
+                    error,
+                ),
+            ));
+        }
+    }
+
+    // Replace whatever a previous owner left behind with a description of this process. The
+    // `unwrap` is safe because serialization should never fail.
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&serde_json::to_vec(owner).unwrap())?;
+    file.sync_all()?;
+
+    Ok(file)
+}
+
+// Describe the process holding a lock, based on what it recorded in the lock file.
+fn describe_owner(lock_file_path: &Path) -> String {
+    // Some platforms don't let other processes read a locked file, in which case we can only say
+    // where the lock is.
+    match read(lock_file_path)
+        .ok()
+        .and_then(|contents| serde_json::from_slice::<Owner>(&contents).ok())
+    {
+        Some(owner) => format!(
+            "Lock file `{}` is held by process {} (node {} at {}, version {}).",
+            lock_file_path.to_string_lossy(),
+            owner.pid,
+            owner.node_index,
+            owner.address,
+            owner.version,
+        ),
+        None => format!("Lock file `{}` is held.", lock_file_path.to_string_lossy()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        lock::{Owner, acquire},
+        test_dir::TestDir,
+    };
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+    };
+
+    #[test]
+    fn lock_is_exclusive() {
+        let data_dir = TestDir::new("lock");
+        let data_file_path = data_dir.path().join("127.0.0.1-3000");
+        let owner = Owner::current(
+            0,
+            &Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)),
+        );
+
+        let lock = acquire(&data_file_path, &owner).unwrap();
+        assert_eq!(
+            acquire(&data_file_path, &owner).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock,
+        );
+        drop(lock);
+        assert!(acquire(&data_file_path, &owner).is_ok());
+    }
+}
@@ -0,0 +1,337 @@
+use crate::{
+    address::Address,
+    instances::{Instances, validate_key},
+    node::Node,
+    register::{self, Register},
+    state::ProposalNumber,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// The namespace of the instances which hold the locks. Each lock is a register in its own instance.
+pub const LEASES_NAMESPACE: &str = "leases";
+
+// The endpoint for each lock, like `/locks/foo`
+pub const LOCKS_ENDPOINT: &str = "/locks";
+
+// The lease on a lock. The expiration time is in milliseconds since the Unix epoch, according to the
+// clock of the node which granted or renewed the lease.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lease {
+    pub holder: String,
+    pub token: u64,
+    pub expires_at: u64,
+}
+
+// An operation on a lock
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+    // Take the lock if it's free (or already held by the same holder), with a new fencing token.
+    Acquire {
+        holder: String,
+        ttl_seconds: f64,
+    },
+
+    // Extend the lease, which must still be the one with the given fencing token.
+    Renew {
+        holder: String,
+        token: u64,
+        ttl_seconds: f64,
+    },
+
+    // Give up the lease, which must still be the one with the given fencing token.
+    Release {
+        holder: String,
+        token: u64,
+    },
+}
+
+// The locks hosted by a node
+#[derive(Clone)]
+pub struct Locks {
+    pub instances: Instances,
+    max_clock_skew: Duration,
+}
+
+impl Locks {
+    // Open the instances which hold the locks. Leases are only considered expired once the clocks
+    // of all the nodes agree that they are, assuming the clocks are at most `max_clock_skew` apart.
+    pub async fn open(data_file_path: &Path, max_clock_skew: Duration) -> io::Result<Self> {
+        Ok(Self {
+            instances: Instances::open(data_file_path, LEASES_NAMESPACE).await?,
+            max_clock_skew,
+        })
+    }
+
+    // Read a lock, or perform an operation on it. Either way, this runs a round of the protocol, so
+    // the result reflects every operation which finished before it started. Returns the current
+    // lease, if any, and the reason the operation couldn't be performed, if applicable.
+    pub async fn run(
+        &self,
+        node: &Node,
+        name: &str,
+        operation: Option<&Operation>,
+    ) -> io::Result<(Option<Lease>, Option<String>)> {
+        let (state, data_file_path) = self.instances.get(name).await?;
+
+        // Locks are never chosen, so they're never compacted.
+        let data_file_path = data_file_path.unwrap_or_else(|| self.instances.path(name));
+
+        let (register, rejection) = register::change(
+            node,
+            state,
+            &data_file_path,
+            &self.instances.endpoint_prefix(name),
+            |register, proposal_number| {
+                let Some(operation) = operation else {
+                    return Ok(register.clone());
+                };
+                let lease = decode(register)?;
+                let lease = apply(
+                    operation,
+                    lease,
+                    now(),
+                    token(&node.nodes, proposal_number)?,
+                    self.max_clock_skew,
+                )?;
+                Ok(Register {
+                    version: register.version + 1,
+                    value: lease.map(|lease| {
+                        // The `unwrap` is safe because serialization should never fail.
+                        serde_json::to_string(&lease).unwrap()
+                    }),
+                })
+            },
+            None,
+        )
+        .await?;
+
+        let lease = decode(&register).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unable to read lock `{name}`. Reason: {error}"),
+            )
+        })?;
+        Ok((lease, rejection))
+    }
+}
+
+// Determine which lock an endpoint is for, if any.
+pub fn name(path: &str) -> Option<Result<&str, String>> {
+    let name = path.strip_prefix(LOCKS_ENDPOINT)?.strip_prefix('/')?;
+    Some(validate_key(name).map(|()| name))
+}
+
+// Read the lease from a register.
+fn decode(register: &Register) -> Result<Option<Lease>, String> {
+    register
+        .value
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|error| format!("The lease is malformed. Reason: {error}"))
+}
+
+// Derive a fencing token from a proposal number. Tokens are ordered the same way as the proposal
+// numbers they come from, so each lease gets a larger token than every lease granted before it.
+fn token(nodes: &[Address], proposal_number: &ProposalNumber) -> Result<u64, String> {
+    let mut addresses = nodes.iter().collect::<Vec<_>>();
+    addresses.sort();
+    let rank = addresses
+        .iter()
+        .position(|address| **address == proposal_number.proposer_address)
+        .ok_or_else(|| "The proposer isn't in the cluster.".to_owned())?;
+
+    u64::try_from(nodes.len())
+        .ok()
+        .and_then(|node_count| proposal_number.round.checked_mul(node_count))
+        .and_then(|token| token.checked_add(u64::try_from(rank).ok()?))
+        .ok_or_else(|| "The fencing token would overflow.".to_owned())
+}
+
+// Determine the current time in milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| {
+            duration.as_millis().try_into().unwrap_or(u64::MAX)
+        })
+}
+
+// Determine the lease after an operation, given the current one and the current time.
+fn apply(
+    operation: &Operation,
+    lease: Option<Lease>,
+    now: u64,
+    token: u64,
+    max_clock_skew: Duration,
+) -> Result<Option<Lease>, String> {
+    let expires_at = |ttl_seconds: f64| {
+        Duration::try_from_secs_f64(ttl_seconds)
+            .ok()
+            .and_then(|ttl| u64::try_from(ttl.as_millis()).ok())
+            .and_then(|ttl| now.checked_add(ttl))
+            .ok_or_else(|| format!("`{ttl_seconds}` is not a valid number of seconds."))
+    };
+    let max_clock_skew = u64::try_from(max_clock_skew.as_millis()).unwrap_or(u64::MAX);
+
+    match operation {
+        Operation::Acquire {
+            holder,
+            ttl_seconds,
+        } => {
+            if let Some(lease) = &lease
+                && lease.holder != *holder
+                && now <= lease.expires_at.saturating_add(max_clock_skew)
+            {
+                return Err(format!(
+                    "The lock is held by `{}` with token {}.",
+                    lease.holder, lease.token,
+                ));
+            }
+
+            Ok(Some(Lease {
+                holder: holder.clone(),
+                token,
+                expires_at: expires_at(*ttl_seconds)?,
+            }))
+        }
+        Operation::Renew {
+            holder,
+            token,
+            ttl_seconds,
+        } => match lease {
+            // The lease can be renewed even if it expired, as long as no one else took the lock.
+            Some(lease) if lease.holder == *holder && lease.token == *token => Ok(Some(Lease {
+                expires_at: expires_at(*ttl_seconds)?,
+                ..lease
+            })),
+            _ => Err(format!(
+                "The lock isn't held by `{holder}` with token {token}."
+            )),
+        },
+        Operation::Release { holder, token } => match lease {
+            Some(lease) if lease.holder == *holder && lease.token == *token => Ok(None),
+            _ => Err(format!(
+                "The lock isn't held by `{holder}` with token {token}."
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        locks::{Lease, Operation, apply, token},
+        state::ProposalNumber,
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    const SKEW: Duration = Duration::from_secs(1);
+
+    fn lease(holder: &str, token: u64, expires_at: u64) -> Lease {
+        Lease {
+            holder: holder.to_owned(),
+            token,
+            expires_at,
+        }
+    }
+
+    fn acquire(holder: &str) -> Operation {
+        Operation::Acquire {
+            holder: holder.to_owned(),
+            ttl_seconds: 10.0_f64,
+        }
+    }
+
+    #[test]
+    fn acquire_free_lock() {
+        assert_eq!(
+            apply(&acquire("alice"), None, 1_000, 7, SKEW),
+            Ok(Some(lease("alice", 7, 11_000))),
+        );
+    }
+
+    #[test]
+    fn acquire_held_lock() {
+        let held = Some(lease("alice", 7, 11_000));
+        assert!(apply(&acquire("bob"), held.clone(), 11_000, 8, SKEW).is_err());
+
+        // The lease isn't considered expired until the clocks of all the nodes agree that it is.
+        assert!(apply(&acquire("bob"), held.clone(), 12_000, 8, SKEW).is_err());
+        assert_eq!(
+            apply(&acquire("bob"), held.clone(), 12_001, 8, SKEW),
+            Ok(Some(lease("bob", 8, 22_001))),
+        );
+
+        // The holder can acquire the lock again, with a new token.
+        assert_eq!(
+            apply(&acquire("alice"), held, 5_000, 8, SKEW),
+            Ok(Some(lease("alice", 8, 15_000))),
+        );
+    }
+
+    #[test]
+    fn renew_and_release() {
+        let held = Some(lease("alice", 7, 11_000));
+        let renew = |token| Operation::Renew {
+            holder: "alice".to_owned(),
+            token,
+            ttl_seconds: 10.0_f64,
+        };
+        let release = |token| Operation::Release {
+            holder: "alice".to_owned(),
+            token,
+        };
+
+        assert_eq!(
+            apply(&renew(7), held.clone(), 5_000, 8, SKEW),
+            Ok(Some(lease("alice", 7, 15_000))),
+        );
+        assert!(apply(&renew(6), held.clone(), 5_000, 8, SKEW).is_err());
+        assert!(apply(&renew(7), None, 5_000, 8, SKEW).is_err());
+        assert_eq!(apply(&release(7), held.clone(), 5_000, 8, SKEW), Ok(None));
+        assert!(apply(&release(6), held, 5_000, 8, SKEW).is_err());
+    }
+
+    #[test]
+    fn tokens_follow_proposal_numbers() {
+        let address = |port| Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+        let nodes = [address(3002), address(3000), address(3001)];
+        let proposal_number = |round, port| ProposalNumber {
+            round,
+            proposer_address: address(port),
+        };
+
+        let mut proposal_numbers = [
+            proposal_number(0, 3000),
+            proposal_number(0, 3002),
+            proposal_number(1, 3001),
+            proposal_number(1, 3000),
+            proposal_number(2, 3002),
+        ];
+        proposal_numbers.sort();
+        let tokens = proposal_numbers
+            .iter()
+            .map(|proposal_number| token(&nodes, proposal_number).unwrap())
+            .collect::<Vec<_>>();
+        assert!(tokens.is_sorted());
+        assert_eq!(
+            tokens.len(),
+            tokens
+                .iter()
+                .collect::<std::collections::BTreeSet<_>>()
+                .len()
+        );
+    }
+}
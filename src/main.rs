@@ -1,36 +1,85 @@
 mod acceptor;
+mod address;
+mod audit;
+mod backoff;
+mod chaos;
+#[cfg(test)]
+mod cluster;
 mod config;
+mod dashboard;
+mod health;
+mod instances;
+mod lock;
+mod locks;
+mod node;
+mod peers;
 mod proposer;
+mod register;
 mod rpc;
+mod shutdown;
 mod state;
+mod store;
+#[cfg(test)]
+mod test_dir;
+mod trace;
+mod transport;
 
 #[macro_use]
 extern crate log;
 
-use acceptor::acceptor;
-use clap::{ArgAction, Parser};
+use acceptor::{Handlers, acceptor};
+use address::Address;
+use audit::AuditLog;
+use backoff::Backoff;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use env_logger::{Builder, fmt::style::Effects};
-use log::{Level, LevelFilter};
-use proposer::propose;
-use state::initial;
+use instances::{INSTANCES_NAMESPACE, Instances};
+use locks::Locks;
+use log::{
+    Level, LevelFilter,
+    kv::{self, Key, Value, VisitSource},
+};
+use node::Node;
+use peers::Peers;
+use proposer::{open_fast_ballot, propose, propose_fast};
+use register::LeaderLease;
+use rpc::new_client;
+use serde::Serialize;
+use shutdown::Shutdown;
+use state::{LeaseGrant, ProposalNumber, initial};
 use std::{
     env,
     io::{self, Write},
     net::SocketAddr,
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
     process::exit,
     str::FromStr,
     string::ToString,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::{sync::RwLock, time::sleep, try_join};
+use trace::Tracer;
+use transport::{Connector, Listener};
 
 // Defaults
 const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: &str = "10";
+const DEFAULT_LINGER: &str = "0";
+const DEFAULT_MAX_CLOCK_SKEW: &str = "0.5";
+const DEFAULT_RETRY_DELAY: &str = "0.1";
+const DEFAULT_MAX_RETRY_DELAY: &str = "1";
+const DEFAULT_FAST_RETRY_DELAY: &str = "0.2";
+const DEFAULT_STRAGGLER_LINGER: &str = "1";
+const DEFAULT_RETRY_BUDGET: u32 = 10;
+const DEFAULT_PEER_PROBE_INTERVAL: &str = "5";
+const DEFAULT_PROPOSER_LOOP_DELAY: &str = "1";
+
+// Exit codes
+const EXIT_CODE_NOT_CHOSEN: i32 = 3;
 
-// Duration constants
-const PROPOSER_LOOP_DELAY: Duration = Duration::from_secs(1);
+// The node which opens a fast ballot when using Fast Paxos
+const FAST_PAXOS_COORDINATOR: usize = 0;
 
 // This struct represents the raw command-line arguments.
 #[derive(Parser)]
@@ -42,8 +91,11 @@ const PROPOSER_LOOP_DELAY: Duration = Duration::from_secs(1);
         env!("CARGO_PKG_HOMEPAGE")
     ),
     version,
-    disable_version_flag = true
+    disable_version_flag = true,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
 )]
+#[allow(clippy::struct_excessive_bools)]
 struct Cli {
     #[arg(short, long, help = "Print version", action = ArgAction::Version)]
     _version: Option<bool>,
@@ -55,7 +107,7 @@ struct Cli {
         help = "Set the index of the node corresponding to this instance",
         required = true
     )]
-    node: String,
+    node: Option<String>,
 
     #[arg(
         short = 'x',
@@ -65,6 +117,29 @@ struct Cli {
     )]
     propose: Option<String>,
 
+    #[arg(
+        long,
+        help = "Use Fast Paxos: node 0 opens a fast ballot, and values are sent directly to the \
+            nodes"
+    )]
+    fast: bool,
+
+    #[arg(
+        long,
+        help = "Serve a replicated register which clients can read and change at /register, rather \
+            than choosing a single value",
+        conflicts_with_all = ["propose", "fast", "exit_after_chosen"]
+    )]
+    register: bool,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        help = "Propose to and learn from the named instance rather than the node's own instance",
+        conflicts_with_all = ["fast", "register"]
+    )]
+    instance: Option<String>,
+
     #[arg(
         short,
         long,
@@ -97,20 +172,258 @@ struct Cli {
         help = "Set the port to run on (if different from the configuration)"
     )]
     port: Option<String>,
+
+    #[arg(
+        short = 'g',
+        long,
+        value_name = "SECONDS",
+        help = "Set how long to wait for open connections to finish when shutting down",
+        default_value = DEFAULT_SHUTDOWN_GRACE_PERIOD
+    )]
+    grace_period: String,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set how far apart the clocks of the nodes may be, which lock leases account for",
+        default_value = DEFAULT_MAX_CLOCK_SKEW
+    )]
+    max_clock_skew: String,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set the most a proposer waits after losing a round, which doubles with every round \
+            it loses in a row",
+        default_value = DEFAULT_RETRY_DELAY
+    )]
+    retry_delay: String,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set the most a proposer waits after losing any number of rounds",
+        default_value = DEFAULT_MAX_RETRY_DELAY
+    )]
+    max_retry_delay: String,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set how long to wait between attempts to open a fast ballot or send a value in one",
+        default_value = DEFAULT_FAST_RETRY_DELAY
+    )]
+    fast_retry_delay: String,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set how long to let requests to the nodes outside a quorum finish in the background \
+            after the quorum responds",
+        default_value = DEFAULT_STRAGGLER_LINGER
+    )]
+    straggler_linger: String,
+
+    #[arg(
+        long,
+        help = "Send requests to other nodes over HTTP/2, which multiplexes them over one \
+            connection per node (nodes accept both HTTP/1.1 and HTTP/2 either way)"
+    )]
+    http2: bool,
+
+    #[arg(
+        long,
+        value_name = "COUNT",
+        help = "Set how many times the requests for each phase of a round may be retried, across \
+            all the nodes",
+        default_value_t = DEFAULT_RETRY_BUDGET
+    )]
+    retry_budget: u32,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set how often to probe a node which is considered down, rather than sending it \
+            requests",
+        default_value = DEFAULT_PEER_PROBE_INTERVAL
+    )]
+    peer_probe_interval: String,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Set how long to wait before proposing again when no value was found to propose",
+        default_value = DEFAULT_PROPOSER_LOOP_DELAY
+    )]
+    proposer_loop_delay: String,
+
+    #[arg(
+        short,
+        long,
+        help = "Exit once a value has been chosen, reporting it as JSON"
+    )]
+    exit_after_chosen: bool,
+
+    #[arg(
+        short,
+        long,
+        value_name = "SECONDS",
+        help = "Set how long to keep serving other nodes after a value has been chosen before \
+            exiting",
+        default_value = DEFAULT_LINGER,
+        requires = "exit_after_chosen"
+    )]
+    linger: String,
+
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        help = "Write the chosen value as JSON to this file rather than to STDOUT",
+        requires = "exit_after_chosen"
+    )]
+    result_file: Option<PathBuf>,
+
+    #[arg(
+        short = 'f',
+        long,
+        value_name = "FORMAT",
+        help = "Set the format of log messages",
+        default_value = "text"
+    )]
+    log_format: LogFormat,
+
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        help = "Append trace spans to this file in OTLP/JSON format"
+    )]
+    trace_file: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "URL",
+        help = "Send trace spans to this OTLP/HTTP endpoint (e.g., \
+            http://localhost:4318/v1/traces)"
+    )]
+    otlp_endpoint: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        help = "Append a record of every request handled by the acceptor to this file"
+    )]
+    audit_log: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// Subcommands, which are tools for working with a cluster rather than ways of running a node
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Print audit logs and check them against the invariants of Paxos")]
+    Audit {
+        #[arg(
+            value_name = "PATH",
+            help = "The audit logs to check, one per node",
+            required = true
+        )]
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(
+        about = "Run a proxy in front of a node which drops, delays, duplicates, or reorders the \
+            messages sent to it"
+    )]
+    ChaosProxy {
+        #[arg(long, value_name = "ADDRESS", help = "Set the address to listen on")]
+        listen: Address,
+
+        #[arg(
+            long,
+            value_name = "ADDRESS",
+            help = "Set the address of the node to forward messages to"
+        )]
+        target: Address,
+
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Set the path to a YAML file of rules for disrupting messages (without one, \
+                messages are forwarded untouched)"
+        )]
+        script: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_name = "SEED",
+            help = "Seed the random choices of which messages to disrupt, overriding the script"
+        )]
+        seed: Option<u64>,
+    },
+}
+
+// The format of log messages
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    // Human-readable text, colored and wrapped to fit the terminal
+    Text,
+
+    // One JSON object per line, for consumption by log processing tools
+    Json,
 }
 
 // This struct represents the parsed command-line arguments.
 #[derive(Clone)]
 struct Settings {
-    nodes: Vec<SocketAddr>,
+    nodes: Vec<Address>,
     node_index: usize,
-    address: SocketAddr,
+    address: Address,
     proposal: Option<String>,
+    fast: bool,
+    register: bool,
+    leader_lease: Option<LeaderLease>,
+    instance: Option<String>,
     data_file_path: PathBuf,
+    shutdown_grace_period: Duration,
+    max_clock_skew: Duration,
+    retry_delays: backoff::Delays,
+    peer_policy: peers::Policy,
+    proposer_loop_delay: Duration,
+    exit_after_chosen: bool,
+    linger: Duration,
+    result_file: Option<PathBuf>,
+    trace_file: Option<PathBuf>,
+    otlp_endpoint: Option<String>,
+    audit_log: Option<PathBuf>,
+}
+
+// The result reported in JSON once a value has been chosen
+#[derive(Serialize)]
+struct Outcome<'a> {
+    value: &'a str,
+    proposal_number: &'a ProposalNumber,
+}
+
+// This visitor collects the key-value pairs attached to a log record.
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((
+            key.to_string(),
+            serde_json::to_value(value).unwrap_or(serde_json::Value::Null),
+        ));
+        Ok(())
+    }
 }
 
-// Set up the logger.
-fn set_up_logging() {
+// Set up the logger. The node index is included in JSON log messages if it's valid.
+fn set_up_logging(log_format: LogFormat, node_index: Option<usize>) {
     Builder::new()
         .filter_module(
             module_path!(),
@@ -119,40 +432,71 @@ fn set_up_logging() {
             )
             .unwrap_or(DEFAULT_LOG_LEVEL),
         )
-        .format(|buf, record| {
-            let level_for_style = match record.level() {
-                Level::Trace => Level::Debug,
-                level => level,
-            };
-            let style = buf
-                .default_level_style(level_for_style)
-                .effects(Effects::BOLD);
-            let indent_size = record.level().to_string().len() + 3;
-            let indent = &" ".repeat(indent_size);
-            let options = textwrap::Options::with_termwidth()
-                .initial_indent(indent)
-                .subsequent_indent(indent);
-            writeln!(
-                buf,
-                "{style}[{}]{style:#} {}",
-                record.level(),
-                &textwrap::fill(&record.args().to_string(), options)[indent_size..],
-            )
+        .format(move |buf, record| {
+            // Collect the structured data for the message. The `unwrap_or` is safe because the
+            // visitor never fails.
+            let mut fields = Fields(vec![]);
+            record.key_values().visit(&mut fields).unwrap_or(());
+
+            match log_format {
+                LogFormat::Text => {
+                    // Render the structured data on separate lines below the message.
+                    let mut lines = vec![record.args().to_string()];
+                    for (key, value) in fields.0 {
+                        let value = match value {
+                            serde_json::Value::Null => continue,
+                            serde_json::Value::String(value) => value,
+                            value => value.to_string(),
+                        };
+                        lines.push(format!("{key}: {value}"));
+                    }
+                    let message = lines.join("\n");
+
+                    let level_for_style = match record.level() {
+                        Level::Trace => Level::Debug,
+                        level => level,
+                    };
+                    let style = buf
+                        .default_level_style(level_for_style)
+                        .effects(Effects::BOLD);
+                    let indent_size = record.level().to_string().len() + 3;
+                    let indent = &" ".repeat(indent_size);
+                    let options = textwrap::Options::with_termwidth()
+                        .initial_indent(indent)
+                        .subsequent_indent(indent);
+                    writeln!(
+                        buf,
+                        "{style}[{}]{style:#} {}",
+                        record.level(),
+                        &textwrap::fill(&message, options)[indent_size..],
+                    )
+                }
+                LogFormat::Json => {
+                    let mut event = serde_json::Map::new();
+                    event.insert(
+                        "timestamp".to_owned(),
+                        buf.timestamp_millis().to_string().into(),
+                    );
+                    event.insert("level".to_owned(), record.level().as_str().into());
+                    event.insert("node_index".to_owned(), node_index.into());
+                    event.insert("message".to_owned(), record.args().to_string().into());
+                    event.extend(fields.0);
+                    writeln!(buf, "{}", serde_json::Value::Object(event))
+                }
+            }
         })
         .init();
 }
 
 // Parse the command-line options.
 #[allow(clippy::too_many_lines)]
-async fn settings() -> io::Result<Settings> {
-    let cli = Cli::parse();
-
+async fn settings(cli: Cli) -> io::Result<Settings> {
     // Parse the config file.
     let config = config::read(&cli.config_file).await?;
 
-    // Parse the node index. Clap already guarantees that this required positional argument is
-    // present.
-    let node_repr = &cli.node;
+    // Parse the node index. Clap already guarantees that this required argument is present unless
+    // a subcommand was given, in which case we don't get here.
+    let node_repr = cli.node.as_deref().unwrap_or_default();
     let node_index: usize = node_repr.parse().map_err(|error| {
         io::Error::new(
             io::ErrorKind::InvalidInput,
@@ -167,53 +511,288 @@ async fn settings() -> io::Result<Settings> {
         ));
     }
 
-    // Parse the IP address, if given.
-    let ip = cli.ip.as_deref().map_or_else(
-        || Ok(config.nodes[node_index].ip()), // [ref:node_index_valid]
-        |raw_ip| {
-            raw_ip.parse().map_err(|error| {
-                io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("`{raw_ip}` is not a valid IP address. Reason: {error}"),
-                )
-            })
-        },
-    )?;
+    // Determine the address to run on and the data file path [tag:data_file_path_has_parent].
+    let (address, data_file_path) = match &config.nodes[node_index] {
+        // [ref:node_index_valid]
+        Address::Tcp(configured_address) => {
+            // Parse the IP address, if given.
+            let ip = cli.ip.as_deref().map_or_else(
+                || Ok(configured_address.ip()),
+                |raw_ip| {
+                    raw_ip.parse().map_err(|error| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("`{raw_ip}` is not a valid IP address. Reason: {error}"),
+                        )
+                    })
+                },
+            )?;
 
-    // Parse the port number, if given.
-    let port = cli.port.as_deref().map_or_else(
-        || Ok(config.nodes[node_index].port()), // [ref:node_index_valid]
-        |raw_port| {
-            raw_port.parse().map_err(|error| {
-                io::Error::new(
+            // Parse the port number, if given.
+            let port = cli.port.as_deref().map_or_else(
+                || Ok(configured_address.port()),
+                |raw_port| {
+                    raw_port.parse().map_err(|error| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("`{raw_port}` is not a valid port number. Reason: {error}"),
+                        )
+                    })
+                },
+            )?;
+
+            (
+                Address::Tcp(SocketAddr::new(ip, port)),
+                cli.data_dir.join(format!("{ip}-{port}")),
+            )
+        }
+        Address::Unix(path) => {
+            // The IP address and port number don't apply to Unix domain sockets.
+            if cli.ip.is_some() || cli.port.is_some() {
+                return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    format!("`{raw_port}` is not a valid port number. Reason: {error}"),
-                )
-            })
-        },
-    )?;
+                    format!(
+                        "Node {node_index} listens on a Unix domain socket, so it has no IP \
+                        address or port.",
+                    ),
+                ));
+            }
+
+            (
+                Address::Unix(path.clone()),
+                cli.data_dir
+                    .join(format!("unix-{}", unix_data_file_name(path))),
+            )
+        }
+    };
 
-    // Determine the data file path [tag:data_file_path_has_parent].
-    let data_file_path = cli.data_dir.join(format!("{ip}-{port}"));
+    // Validate the name of the instance, if given.
+    if let Some(instance) = &cli.instance {
+        instances::validate_key(instance)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    }
+
+    // Parse the durations.
+    let shutdown_grace_period = parse_seconds(&cli.grace_period)?;
+    let linger = parse_seconds(&cli.linger)?;
+    let max_clock_skew = parse_seconds(&cli.max_clock_skew)?;
+    let retry_delays = backoff::Delays {
+        initial: parse_seconds(&cli.retry_delay)?,
+        max: parse_seconds(&cli.max_retry_delay)?,
+        fast: parse_seconds(&cli.fast_retry_delay)?,
+    };
+    let peer_policy = peers::Policy {
+        http2: cli.http2,
+        straggler_linger: parse_seconds(&cli.straggler_linger)?,
+        retry_budget: cli.retry_budget,
+        probe_interval: parse_seconds(&cli.peer_probe_interval)?,
+    };
+    let proposer_loop_delay = parse_seconds(&cli.proposer_loop_delay)?;
 
     // Return the settings.
     Ok(Settings {
         nodes: config.nodes,
         node_index,
-        address: SocketAddr::new(ip, port),
+        address,
         proposal: cli.propose,
+        fast: cli.fast,
+        register: cli.register,
+        leader_lease: config
+            .leader_lease
+            .filter(|_| cli.register)
+            .map(LeaderLease::new),
+        instance: cli.instance,
         data_file_path,
+        shutdown_grace_period,
+        max_clock_skew,
+        retry_delays,
+        peer_policy,
+        proposer_loop_delay,
+        exit_after_chosen: cli.exit_after_chosen,
+        linger,
+        result_file: cli.result_file,
+        trace_file: cli.trace_file,
+        otlp_endpoint: cli.otlp_endpoint,
+        audit_log: cli.audit_log,
     })
 }
 
+// Derive the name of the data file for a node listening on a Unix domain socket from the whole
+// socket path, so nodes with sockets of the same name in different directories don't share a data
+// file. The components are joined with `-`, so `%` and `-` within a component are percent-encoded
+// to keep distinct paths from mapping to the same name. An absolute path starts with `-`.
+fn unix_data_file_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::RootDir => Some(String::new()),
+            Component::CurDir => None,
+            Component::ParentDir => Some("..".to_owned()),
+            Component::Prefix(_) | Component::Normal(_) => Some(
+                component
+                    .as_os_str()
+                    .to_string_lossy()
+                    .replace('%', "%25")
+                    .replace('-', "%2D"),
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Parse a nonnegative number of seconds.
+fn parse_seconds(raw_seconds: &str) -> io::Result<Duration> {
+    raw_seconds
+        .parse::<f64>()
+        .map_err(|error| error.to_string())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).map_err(|error| error.to_string()))
+        .map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{raw_seconds}` is not a valid number of seconds. Reason: {error}"),
+            )
+        })
+}
+
+// Run the proposer until a value is known to have been chosen or a shutdown is requested. Even if
+// there's no value to propose, we run the proposer periodically to learn if a value was chosen and
+// let the other nodes know about it. With Fast Paxos, the value is sent directly to the nodes first,
+// and the classic rounds only run if that doesn't work.
+async fn learn(
+    node: &Node,
+    state: Arc<RwLock<(state::Durable, state::Volatile)>>,
+    data_file_path: &Path,
+    endpoint_prefix: &str,
+    settings: &Settings,
+) -> io::Result<Option<(ProposalNumber, String)>> {
+    if settings.fast {
+        if settings.node_index == FAST_PAXOS_COORDINATOR {
+            open_fast_ballot(node).await?;
+        }
+
+        if let Some(value) = &settings.proposal
+            && let Some(chosen_proposal) = propose_fast(node, value).await
+        {
+            return Ok(Some(chosen_proposal));
+        }
+    }
+
+    // The proposer returns right away if the value is already known (e.g., for a compacted
+    // instance).
+    loop {
+        if let Some(chosen_proposal) = propose(
+            node,
+            state.clone(),
+            data_file_path,
+            endpoint_prefix,
+            settings.proposal.as_deref(),
+        )
+        .await?
+        {
+            return Ok(Some(chosen_proposal));
+        }
+
+        if node.shutdown.is_requested()
+            || node
+                .shutdown
+                .interrupt(sleep(settings.proposer_loop_delay))
+                .await
+                .is_none()
+        {
+            return Ok(None);
+        }
+    }
+}
+
+// Report the chosen value. Normally it's printed to STDOUT as is, but when the program is going to
+// exit after a value is chosen, it's reported as JSON along with the number of the proposal which
+// chose it.
+async fn report(chosen_proposal: &(ProposalNumber, String), settings: &Settings) -> io::Result<()> {
+    let (proposal_number, value) = chosen_proposal;
+    if !settings.exit_after_chosen {
+        println!("{value}");
+        io::stdout().flush().unwrap_or(());
+        return Ok(());
+    }
+
+    // The `unwrap` is safe because serialization should never fail.
+    let outcome = serde_json::to_string(&Outcome {
+        value,
+        proposal_number,
+    })
+    .unwrap();
+
+    if let Some(result_file) = &settings.result_file {
+        write_result_file(result_file, &outcome).await
+    } else {
+        println!("{outcome}");
+        io::stdout().flush().unwrap_or(());
+        Ok(())
+    }
+}
+
+// Write the JSON result to a file.
+async fn write_result_file(path: &Path, outcome: &str) -> io::Result<()> {
+    tokio::fs::write(path, format!("{outcome}\n"))
+        .await
+        .map_err(|error| {
+            io::Error::other(format!(
+                "Unable to write result file `{}`. Reason: {}",
+                path.to_string_lossy(),
+                error,
+            ))
+        })
+}
+
+// Run a subcommand and exit.
+async fn run_command(command: &Command) -> ! {
+    match command {
+        Command::Audit { paths } => match audit::inspect(paths).await {
+            Ok(true) => exit(0),
+            Ok(false) => exit(1),
+            Err(error) => {
+                error!("{error}");
+                exit(1);
+            }
+        },
+        Command::ChaosProxy {
+            listen,
+            target,
+            script,
+            seed,
+        } => {
+            let result = async {
+                let script = match script {
+                    Some(path) => chaos::read_script(path).await?,
+                    None => chaos::Script::default(),
+                };
+                chaos::run(listen, target, script, *seed).await
+            }
+            .await;
+            if let Err(error) = result {
+                error!("{error}");
+            }
+            exit(1);
+        }
+    }
+}
+
 // Let the fun begin!
 #[tokio::main]
 async fn main() {
-    // Set up the logger.
-    set_up_logging();
+    // Parse the command-line arguments, and set up the logger so the rest of the arguments can be
+    // validated with proper error reporting.
+    let cli = Cli::parse();
+    set_up_logging(
+        cli.log_format,
+        cli.node.as_deref().and_then(|node| node.parse().ok()),
+    );
 
-    // Parse the command-line arguments.
-    let settings = match settings().await {
+    // Run the subcommand instead of a node, if one was given.
+    if let Some(command) = &cli.command {
+        run_command(command).await;
+    }
+
+    let settings = match settings(cli).await {
         Ok(settings) => settings,
         Err(error) => {
             error!("{error}");
@@ -221,6 +800,38 @@ async fn main() {
         }
     };
 
+    // Run the node until it's asked to shut down.
+    let chosen_proposal = match run(&settings, None, &Shutdown::listen()).await {
+        Ok(chosen_proposal) => chosen_proposal,
+        Err(error) => {
+            error!("{error}");
+            exit(1);
+        }
+    };
+
+    // Let the caller know if the program was asked to exit after a value was chosen, but it shut
+    // down before that happened.
+    if settings.exit_after_chosen && chosen_proposal.is_none() {
+        exit(EXIT_CODE_NOT_CHOSEN);
+    }
+}
+
+// Run a node until a shutdown is requested, which happens automatically a while after a value is
+// chosen if the program is supposed to exit then. The node listens on its address, unless it's
+// given a listener which is already bound. Returns the chosen proposal, if the node learned it.
+#[allow(clippy::too_many_lines)]
+async fn run(
+    settings: &Settings,
+    listener: Option<Listener>,
+    shutdown: &Shutdown,
+) -> io::Result<Option<(ProposalNumber, String)>> {
+    // Make sure no other process is using the same data file, since two acceptors sharing one
+    // state file could break each other's promises. The lock is held until the node stops.
+    let _lock = lock::acquire(
+        &settings.data_file_path,
+        &lock::Owner::current(settings.node_index, &settings.address),
+    )?;
+
     // Initialize the program state.
     let state = Arc::new(RwLock::new(initial()));
 
@@ -235,53 +846,195 @@ async fn main() {
             if error.kind() == io::ErrorKind::NotFound {
                 info!("Starting from the initial state.");
             } else {
-                error!(
-                    "Unable to load state file `{}`. Reason: {}",
-                    settings.data_file_path.to_string_lossy(),
-                    error,
-                );
-                exit(1);
+                return Err(io::Error::new(
+                    error.kind(),
+                    format!(
+                        "Unable to load state file `{}`. Reason: {}",
+                        settings.data_file_path.to_string_lossy(),
+                        error,
+                    ),
+                ));
             }
         }
     }
 
-    // Run the acceptor and the proposer. Even if there's no value to propose, we run the proposer
-    // periodically to learn if a value was chosen and let the other nodes know about it.
-    if let Err(error) = try_join!(
-        acceptor(state.clone(), &settings.data_file_path, settings.address),
-        async {
-            loop {
-                propose(
-                    state.clone(),
-                    &settings.data_file_path,
-                    &settings.nodes,
-                    settings.node_index,
-                    settings.proposal.as_deref(),
-                )
-                .await?;
-
-                if state.read().await.1.chosen_value.is_some() {
-                    break;
+    // In register mode, this node may have granted a leader lease before it restarted, so it refuses
+    // every proposal until any such lease would have expired.
+    if let Some(leader_lease) = &settings.leader_lease {
+        state.write().await.1.leader_lease = Some(LeaseGrant {
+            holder: None,
+            expires_at: Instant::now() + leader_lease.duration(),
+            contended: true,
+        });
+    }
+
+    // The node hosts any number of named instances in addition to its own.
+    let instances = Instances::open(&settings.data_file_path, INSTANCES_NAMESPACE).await?;
+
+    // The node also hosts the locks.
+    let locks = Locks::open(&settings.data_file_path, settings.max_clock_skew).await?;
+
+    // Determine which instance the proposer works on. A compacted instance has no state file, but
+    // it also has nothing left to propose, since its value is already known.
+    let (proposer_state, proposer_data_file_path, proposer_endpoint_prefix) =
+        match &settings.instance {
+            Some(instance) => match instances.get(instance).await {
+                Ok((instance_state, data_file_path)) => (
+                    instance_state,
+                    data_file_path.unwrap_or_else(|| instances.path(instance)),
+                    instances.endpoint_prefix(instance),
+                ),
+                Err(error) => {
+                    return Err(io::Error::new(
+                        error.kind(),
+                        format!("Unable to load state of instance `{instance}`. Reason: {error}"),
+                    ));
                 }
+            },
+            None => (
+                state.clone(),
+                settings.data_file_path.clone(),
+                String::new(),
+            ),
+        };
+
+    // Start exporting trace spans, if requested.
+    let tracer = Tracer::new(
+        settings.trace_file.as_deref(),
+        settings.otlp_endpoint.as_deref(),
+        &settings.address,
+    )
+    .await?;
+
+    // Open the audit log, if requested.
+    let audit_log = AuditLog::open(settings.audit_log.as_deref()).await?;
+
+    // Everything on this node which sends requests to the other nodes shares one client, so
+    // connections are reused for as long as the node runs.
+    let connector = Connector::default();
+    let connections_opened = connector.opened();
+    let client = new_client(connector, settings.peer_policy.http2);
+
+    // The proposers on this node share their timing, the health of the nodes, and the contention
+    // metrics.
+    let backoff = Backoff::new(
+        settings.retry_delays,
+        Peers::new(
+            &settings.nodes[settings.node_index],
+            &settings.nodes,
+            settings.peer_policy,
+        ),
+    );
+
+    let node = Node {
+        state: state.clone(),
+        data_file_path: settings.data_file_path.clone(),
+        nodes: settings.nodes.clone(),
+        index: settings.node_index,
+        client,
+        connections_opened,
+        backoff,
+        shutdown: shutdown.clone(),
+        tracer: tracer.clone(),
+        audit_log,
+        instances: instances.clone(),
+        locks: locks.clone(),
+        handlers: Handlers::default(),
+    };
+
+    // If the node is killed (by dropping this future) rather than stopped, the requests it's
+    // handling are killed with it.
+    let _abort_handlers = node.handlers.abort_on_drop();
+
+    let listener = match listener {
+        Some(listener) => listener,
+        None => Listener::bind(&settings.address)
+            .await
+            .map_err(|error| io::Error::other(format!("Unable to bind socket. Reason: {error}")))?,
+    };
 
-                sleep(PROPOSER_LOOP_DELAY).await;
+    // Run the acceptor and the proposer. Both stop once a shutdown is requested, which happens
+    // automatically a while after a value is chosen if the program is supposed to exit then.
+    let result = try_join!(
+        acceptor(
+            &node,
+            listener,
+            &settings.address,
+            settings.shutdown_grace_period,
+            settings.fast,
+            settings.register,
+            settings.leader_lease.clone(),
+        ),
+        async {
+            // In register mode, the proposer only runs on behalf of clients of the register.
+            if settings.register {
+                shutdown.requested().await;
+                return Ok(None);
             }
 
-            Ok(())
+            let chosen_proposal = learn(
+                &node,
+                proposer_state,
+                &proposer_data_file_path,
+                &proposer_endpoint_prefix,
+                settings,
+            )
+            .await?;
+
+            if let Some(chosen_proposal) = &chosen_proposal {
+                report(chosen_proposal, settings).await?;
+
+                if settings.exit_after_chosen {
+                    shutdown.interrupt(sleep(settings.linger)).await;
+                    shutdown.request();
+                }
+            }
+
+            Ok(chosen_proposal)
         },
-    ) {
-        error!("{error}");
-        exit(1);
-    }
+    );
+
+    // Apart from the proposer, which has stopped by now, only the handlers change the state. They
+    // run to completion even if their requests are abandoned (e.g., because the client hung up or
+    // the grace period ran out), so waiting for them ensures nothing writes to the state files
+    // after this node stops (e.g., while another one is started with the same data file in this
+    // process) [tag:no_torn_state_writes].
+    node.handlers.close().await;
+    let ((), chosen_proposal) = result?;
+    info!("Shut down gracefully.");
+
+    // Export any spans which haven't been exported yet.
+    tracer.flush().await;
+
+    Ok(chosen_proposal)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{Cli, unix_data_file_name};
     use clap::CommandFactory;
+    use std::path::Path;
 
     #[test]
     fn verify_cli() {
         Cli::command().debug_assert();
     }
+
+    #[test]
+    fn unix_data_file_name_distinguishes_paths() {
+        let names = [
+            "/a/node.sock",
+            "/b/node.sock",
+            "a/node.sock",
+            "/a-b/c.sock",
+            "/a/b-c.sock",
+            "/a/b%2Dc.sock",
+        ]
+        .map(|path| unix_data_file_name(Path::new(path)));
+
+        for (i, name) in names.iter().enumerate() {
+            assert!(!name.contains('/'));
+            assert!(!names[..i].contains(name));
+        }
+    }
 }
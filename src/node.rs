@@ -0,0 +1,41 @@
+use crate::{
+    acceptor::Handlers, address::Address, audit::AuditLog, backoff::Backoff, instances::Instances,
+    locks::Locks, rpc::HttpClient, shutdown::Shutdown, state, trace::Tracer,
+};
+use std::{
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicU64},
+};
+use tokio::sync::RwLock;
+
+// Everything the acceptor and the proposers on a node share. It's cheap to clone, and the clones
+// refer to the same node.
+#[derive(Clone)]
+pub struct Node {
+    // The state of the node's own instance, and where it's persisted
+    pub state: Arc<RwLock<(state::Durable, state::Volatile)>>,
+    pub data_file_path: PathBuf,
+
+    // The addresses of all the nodes in the cluster, and which one is this node
+    pub nodes: Vec<Address>,
+    pub index: usize,
+
+    // The client for sending requests to the other nodes, and how many connections it has opened
+    pub client: HttpClient,
+    pub connections_opened: Arc<AtomicU64>,
+
+    pub backoff: Backoff,
+    pub shutdown: Shutdown,
+    pub tracer: Tracer,
+    pub audit_log: AuditLog,
+    pub instances: Instances,
+    pub locks: Locks,
+    pub handlers: Handlers,
+}
+
+impl Node {
+    // The address of this node
+    pub fn address(&self) -> &Address {
+        &self.nodes[self.index]
+    }
+}
@@ -0,0 +1,202 @@
+use crate::address::Address;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How many requests to a node have to fail in a row before it's considered down
+const FAILURES_BEFORE_DOWN: u32 = 3;
+
+// How the node talks to its peers, and how it deals with ones which are slow or unresponsive
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    // Whether to send requests over HTTP/2 rather than HTTP/1.1
+    pub http2: bool,
+
+    // How long to let the requests to the nodes outside a quorum finish in the background
+    pub straggler_linger: Duration,
+
+    // How many times the requests for one phase of a round may be retried, across all the nodes
+    pub retry_budget: u32,
+
+    // How often to probe a node which is considered down
+    pub probe_interval: Duration,
+}
+
+// What's known about the health of a peer
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+
+    // When the node may be probed next, if it's considered down
+    next_probe: Option<Instant>,
+}
+
+// The health of a peer, as reported by the "state" endpoint
+#[derive(Serialize)]
+pub struct PeerStatus {
+    pub address: Address,
+    pub down: bool,
+    pub consecutive_failures: u32,
+}
+
+// The health of the nodes, shared by everything on the node which sends them requests. Each node has
+// a circuit breaker: once enough requests to a node fail in a row, it's considered down, and requests
+// to it are skipped rather than retried, except for an occasional probe to find out whether it's back
+// up.
+#[derive(Clone)]
+pub struct Peers {
+    address: Address,
+    policy: Policy,
+    health: Arc<Mutex<BTreeMap<Address, Health>>>,
+}
+
+impl Peers {
+    pub fn new(address: &Address, nodes: &[Address], policy: Policy) -> Self {
+        Self {
+            address: address.clone(),
+            policy,
+            health: Arc::new(Mutex::new(
+                nodes
+                    .iter()
+                    .map(|node| (node.clone(), Health::default()))
+                    .collect(),
+            )),
+        }
+    }
+
+    // The address of this node, as the other nodes know it
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    // How long to let the requests to the nodes outside a quorum finish in the background
+    pub fn straggler_linger(&self) -> Duration {
+        self.policy.straggler_linger
+    }
+
+    // How many times the requests for one phase of a round may be retried
+    pub fn retry_budget(&self) -> u32 {
+        self.policy.retry_budget
+    }
+
+    // Determine whether to send a request to a node. Nodes which are considered down are only sent
+    // one request (the probe) per probe interval.
+    pub fn allow(&self, node: &Address) -> bool {
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry(node.clone()).or_default();
+        match health.next_probe {
+            None => true,
+            Some(next_probe) if Instant::now() < next_probe => false,
+            Some(_) => {
+                health.next_probe = Some(Instant::now() + self.policy.probe_interval);
+                true
+            }
+        }
+    }
+
+    // Record whether a request to a node succeeded.
+    pub fn record(&self, node: &Address, succeeded: bool) {
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry(node.clone()).or_default();
+        if succeeded {
+            health.consecutive_failures = 0;
+            if health.next_probe.take().is_some() {
+                info!(node:% = node; "Node is back up.");
+            }
+        } else {
+            health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+            if health.consecutive_failures >= FAILURES_BEFORE_DOWN && health.next_probe.is_none() {
+                health.next_probe = Some(Instant::now() + self.policy.probe_interval);
+                warn!(
+                    node:% = node;
+                    "Node seems to be down. Probing it every {} seconds until it responds.",
+                    self.policy.probe_interval.as_secs_f64(),
+                );
+            }
+        }
+    }
+
+    // Report the health of every node.
+    pub fn status(&self) -> Vec<PeerStatus> {
+        // The `unwrap` is safe since it can only fail if a panic already happened.
+        self.health
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, health)| PeerStatus {
+                address: address.clone(),
+                down: health.next_probe.is_some(),
+                consecutive_failures: health.consecutive_failures,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        peers::{FAILURES_BEFORE_DOWN, Peers, Policy},
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    fn peers(probe_interval: Duration) -> (Address, Peers) {
+        let node = Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000));
+        let peers = Peers::new(
+            &node,
+            std::slice::from_ref(&node),
+            Policy {
+                http2: false,
+                straggler_linger: Duration::ZERO,
+                retry_budget: 0,
+                probe_interval,
+            },
+        );
+        (node, peers)
+    }
+
+    #[test]
+    fn circuit_opens_after_consecutive_failures() {
+        let (node, peers) = peers(Duration::from_mins(1));
+        for _ in 1..FAILURES_BEFORE_DOWN {
+            peers.record(&node, false);
+        }
+        peers.record(&node, true);
+        for _ in 1..FAILURES_BEFORE_DOWN {
+            peers.record(&node, false);
+        }
+        assert!(peers.allow(&node));
+        assert!(!peers.status()[0].down);
+
+        peers.record(&node, false);
+        assert!(!peers.allow(&node));
+        assert!(peers.status()[0].down);
+        assert_eq!(peers.status()[0].consecutive_failures, FAILURES_BEFORE_DOWN);
+    }
+
+    #[test]
+    fn circuit_closes_after_successful_probe() {
+        let (node, peers) = peers(Duration::ZERO);
+        for _ in 0..FAILURES_BEFORE_DOWN {
+            peers.record(&node, false);
+        }
+        assert!(peers.status()[0].down);
+
+        // The probe interval has passed, so the node is probed.
+        assert!(peers.allow(&node));
+        peers.record(&node, false);
+        assert!(peers.status()[0].down);
+        assert!(peers.allow(&node));
+        peers.record(&node, true);
+        assert!(!peers.status()[0].down);
+        assert_eq!(peers.status()[0].consecutive_failures, 0);
+    }
+}
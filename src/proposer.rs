@@ -1,163 +1,813 @@
 use crate::{
     acceptor::{
-        ACCEPT_ENDPOINT, AcceptRequest, AcceptResponse, CHOOSE_ENDPOINT, ChooseRequest,
-        ChooseResponse, PREPARE_ENDPOINT, PrepareRequest, PrepareResponse,
+        ACCEPT_ENDPOINT, AcceptRequest, AcceptResponse, CHOOSE_ENDPOINT, CHOSEN_ENDPOINT,
+        ChooseRequest, ChooseResponse, ChosenRequest, ChosenResponse, FAST_ACCEPT_ENDPOINT,
+        FastAcceptRequest, FastAcceptResponse, OPEN_ENDPOINT, OpenRequest, OpenResponse,
+        PREPARE_ENDPOINT, PrepareRequest, PrepareResponse,
     },
-    rpc::{broadcast_quorum, new_client, try_to_broadcast},
+    address::Address,
+    backoff::winner,
+    node::Node,
+    rpc::{Stragglers, broadcast_quorum, try_to_broadcast},
     state::{self, ProposalNumber},
+    trace::{Kind, Span},
 };
-use rand::RngExt;
-use std::{io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
-use tokio::{sync::RwLock, time::sleep};
+use std::{cmp::Reverse, collections::BTreeMap, io, path::Path, sync::Arc};
+use tokio::{join, sync::RwLock, time::sleep};
 
-// Duration constants
-const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+// How many times a coordinator tries to open a fast ballot, and how many times a client sends its
+// value to the nodes in a fast ballot before falling back to classic rounds (as long as it doesn't
+// see a collision)
+const FAST_ATTEMPTS: usize = 10;
+
+// Determine the size of a fast quorum. That's the smallest number of nodes such that any two fast
+// quorums and any classic quorum (a majority) have a node in common.
+fn fast_quorum_size(node_count: usize) -> usize {
+    let classic_quorum_size = node_count / 2 + 1;
+    (2 * node_count - classic_quorum_size) / 2 + 1
+}
 
 // Generate a new proposal number.
-fn generate_proposal_number(
-    nodes: &[SocketAddr],
+pub fn generate_proposal_number(
+    nodes: &[Address],
     node_index: usize,
     state: &mut state::Durable,
 ) -> ProposalNumber {
     let proposal_number = ProposalNumber {
         round: state.next_round,
-        proposer_address: nodes[node_index],
+        proposer_address: nodes[node_index].clone(),
     };
     state.next_round += 1;
     proposal_number
 }
 
-// Propose a value to the cluster.
+// Make sure the node's next round is past the proposal numbers the nodes have promised to other
+// proposers, so its next proposal isn't rejected from the start.
+pub async fn skip_past<'a>(
+    state: &RwLock<(state::Durable, state::Volatile)>,
+    data_file_path: &Path,
+    min_proposal_numbers: impl IntoIterator<Item = &'a ProposalNumber>,
+) -> io::Result<()> {
+    let Some(round) = min_proposal_numbers
+        .into_iter()
+        .map(|min_proposal_number| min_proposal_number.round)
+        .max()
+    else {
+        return Ok(());
+    };
+
+    let mut guard = state.write().await;
+    if guard.0.next_round <= round {
+        guard.0.next_round = round + 1;
+        crate::state::write(&guard.0, data_file_path).await?;
+    }
+    Ok(())
+}
+
+// Determine which proposal's value must be proposed, given the responses to a prepare request from
+// a quorum. Ordinarily, that's the highest-numbered accepted proposal. But in a fast ballot, nodes
+// may have accepted different values. A value could only have been chosen in that ballot if enough
+// of the quorum accepted it to intersect with a fast quorum, and the quorum sizes ensure there's at
+// most one such value, which is also the most common one. So proposing the most common value in the
+// highest ballot is always safe, and it reduces to the classic rule for classic ballots.
+pub fn select_proposal(prepare_responses: &[PrepareResponse]) -> Option<(ProposalNumber, String)> {
+    let accepted_proposals = prepare_responses
+        .iter()
+        .filter_map(|response| response.accepted_proposal.as_ref());
+    let highest_proposal_number = accepted_proposals
+        .clone()
+        .map(|(proposal_number, _)| proposal_number)
+        .max()?;
+
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for (proposal_number, value) in accepted_proposals {
+        if proposal_number == highest_proposal_number {
+            *counts.entry(value).or_default() += 1;
+        }
+    }
+
+    counts
+        .into_iter()
+        .max_by_key(|(value, count)| (*count, Reverse(*value)))
+        .map(|(value, _)| (highest_proposal_number.clone(), value.to_owned()))
+}
+
+// What a quorum says about the chosen value
+#[derive(Debug, Eq, PartialEq)]
+pub enum QuorumRead {
+    // Every node in the quorum accepted the same proposal, so its value was chosen.
+    Chosen((ProposalNumber, String)),
+
+    // None of the nodes in the quorum accepted anything, so no value has been chosen yet.
+    NothingChosen,
+
+    // The quorum disagrees, so a value may or may not have been chosen.
+    Unknown,
+}
+
+// Interpret the responses to a read-only prepare request from a quorum. In a fast ballot, a value
+// is only chosen once a fast quorum accepts it, so a classic quorum can't prove that it was unless
+// one of them already knows.
+fn interpret_quorum_read(prepare_responses: &[PrepareResponse], fast: bool) -> QuorumRead {
+    if let Some(chosen_proposal) = prepare_responses
+        .iter()
+        .find_map(|response| response.chosen_proposal.as_ref())
+    {
+        return QuorumRead::Chosen(chosen_proposal.clone());
+    }
+
+    let mut accepted_proposals = prepare_responses
+        .iter()
+        .map(|response| response.accepted_proposal.as_ref());
+    let first = accepted_proposals.next().flatten();
+    if !accepted_proposals.all(|accepted_proposal| accepted_proposal == first) {
+        return QuorumRead::Unknown;
+    }
+
+    match first {
+        None => QuorumRead::NothingChosen,
+        Some(_) if fast => QuorumRead::Unknown,
+        Some(accepted_proposal) => QuorumRead::Chosen(accepted_proposal.clone()),
+    }
+}
+
+// Find out what a quorum has accepted with a prepare request that has no proposal number, so the
+// nodes don't make any promises. The endpoints of the instance have the given prefix. Returns
+// nothing if a shutdown was requested first, or an error if a quorum couldn't be reached.
+pub async fn read_quorum(
+    node: &Node,
+    endpoint_prefix: &str,
+    fast: bool,
+) -> Option<io::Result<QuorumRead>> {
+    let mut span = node.tracer.span("quorum read", Kind::Internal);
+    if !endpoint_prefix.is_empty() {
+        span.set("instance", endpoint_prefix);
+    }
+    let prepare_responses = match node
+        .shutdown
+        .interrupt(broadcast_quorum::<PrepareResponse>(
+            &node.client,
+            &node.nodes,
+            &format!("{endpoint_prefix}{PREPARE_ENDPOINT}"),
+            &PrepareRequest {
+                proposal_number: None,
+                lease_duration_ms: None,
+            },
+            node.backoff.peers(),
+            &span,
+        ))
+        .await?
+    {
+        Ok((prepare_responses, _)) => prepare_responses,
+        Err(error) => {
+            span.fail(&error);
+            return Some(Err(error));
+        }
+    };
+    let quorum_read = interpret_quorum_read(&prepare_responses, fast);
+    span.set(
+        "outcome",
+        match quorum_read {
+            QuorumRead::Chosen(_) => "chosen",
+            QuorumRead::NothingChosen => "nothing chosen",
+            QuorumRead::Unknown => "unknown",
+        },
+    );
+    Some(Ok(quorum_read))
+}
+
+// Propose a value to the cluster, either for the node's own instance or for a named one, whose
+// endpoints have the given prefix. The proposer stops as soon as it finds out which value was
+// chosen, whether it learns that from its own rounds, from another proposer, or from the nodes it
+// prepares. Returns the chosen proposal, unless the proposer stopped before it was known (because it
+// had nothing to propose or a shutdown was requested).
+#[allow(clippy::too_many_lines)]
 pub async fn propose(
+    node: &Node,
     state: Arc<RwLock<(state::Durable, state::Volatile)>>,
     data_file_path: &Path,
-    nodes: &[SocketAddr],
-    node_index: usize,
+    endpoint_prefix: &str,
     original_value: Option<&str>,
-) -> Result<(), io::Error> {
-    // Create an HTTP client.
-    let client = new_client();
+) -> io::Result<Option<(ProposalNumber, String)>> {
+    let Node {
+        nodes,
+        index: node_index,
+        client,
+        backoff,
+        shutdown,
+        tracer,
+        ..
+    } = node;
+    let mut rounds = backoff.rounds(node.address());
+
+    // Determine where to send the messages.
+    let prepare_endpoint = format!("{endpoint_prefix}{PREPARE_ENDPOINT}");
+    let accept_endpoint = format!("{endpoint_prefix}{ACCEPT_ENDPOINT}");
+    let chosen_endpoint = format!("{endpoint_prefix}{CHOSEN_ENDPOINT}");
 
-    // Retry until the protocol succeeds.
+    // Check whether this node already knows the chosen value, e.g., because another proposer told
+    // it.
+    let known_chosen_proposal = || async {
+        let chosen_proposal = state.read().await.1.chosen_proposal.clone();
+        if chosen_proposal.is_some() {
+            debug!("The chosen value is already known. Stopping the proposer.");
+        }
+        chosen_proposal
+    };
+
+    // The nodes which didn't make it into the quorums of the previous round
+    let mut prepare_stragglers = None::<Stragglers<PrepareResponse>>;
+    let mut accept_stragglers = None::<Stragglers<AcceptResponse>>;
+
+    // Retry until the protocol succeeds. The RPCs and the sleep are abandoned if a shutdown is
+    // requested, but writes to the state file are always allowed to finish.
     loop {
+        if let Some(chosen_proposal) = known_chosen_proposal().await {
+            return Ok(Some(chosen_proposal));
+        }
+
+        // Take into account what the stragglers said after the previous round moved on without
+        // them. One of them may know the chosen value, and others may have made higher promises.
+        if let Some(chosen_proposal) = prepare_stragglers
+            .as_mut()
+            .map(Stragglers::late_responses)
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|response| response.chosen_proposal)
+        {
+            debug!(value = chosen_proposal.1; "A straggler already knows the chosen value.");
+            let span = tracer.span("late choice", Kind::Internal);
+            notify_chosen(node, endpoint_prefix, &chosen_proposal, &span).await;
+            return Ok(Some(chosen_proposal));
+        }
+        if let Some(stragglers) = &mut accept_stragglers {
+            skip_past(
+                &state,
+                data_file_path,
+                stragglers
+                    .late_responses()
+                    .iter()
+                    .map(|response| &response.min_proposal_number),
+            )
+            .await?;
+        }
+
         // Generate a new proposal number.
         let proposal_number = {
             // The `unwrap` is safe since it can only fail if a panic already happened.
             let mut guard = state.write().await;
-            let proposal_number = generate_proposal_number(nodes, node_index, &mut guard.0);
+            let proposal_number = generate_proposal_number(nodes, *node_index, &mut guard.0);
             crate::state::write(&guard.0, data_file_path).await?;
             proposal_number
         };
+        rounds.start();
+
+        // Each round is traced separately, with a child span for each phase.
+        let mut round_span = tracer.span("round", Kind::Internal);
+        round_span.set("round", &proposal_number.round);
+        round_span.set("proposer", &proposal_number.proposer_address);
+        if !endpoint_prefix.is_empty() {
+            round_span.set("instance", endpoint_prefix);
+        }
 
         // Send a prepare message to all the nodes.
         debug!(
-            "Preparing proposal number:\n{}",
-            // Serialization is safe.
-            yaml_serde::to_string(&proposal_number).unwrap(),
-        );
-        let prepare_responses = broadcast_quorum::<PrepareResponse>(
-            &client,
-            nodes,
-            PREPARE_ENDPOINT,
-            &PrepareRequest {
-                proposal_number: Some(proposal_number),
-            },
-        )
-        .await;
+            endpoint = prepare_endpoint,
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address;
+            "Preparing proposal number.",
+        );
+        let prepare_span = round_span.child("prepare phase", Kind::Internal);
+        let (prepare_responses, stragglers) = match shutdown
+            .interrupt(broadcast_quorum::<PrepareResponse>(
+                client,
+                nodes,
+                &prepare_endpoint,
+                &PrepareRequest {
+                    proposal_number: Some(proposal_number.clone()),
+                    lease_duration_ms: None,
+                },
+                backoff.peers(),
+                &prepare_span,
+            ))
+            .await
+        {
+            None => {
+                round_span.set("outcome", "interrupted");
+                break;
+            }
+            Some(Err(error)) => {
+                debug!(
+                    round = proposal_number.round,
+                    proposer:% = proposal_number.proposer_address;
+                    "{error} Starting over.",
+                );
+                round_span.set("outcome", "unreachable");
+                drop(prepare_span);
+                drop(round_span);
+                if rounds.lose(None, shutdown).await.is_none() {
+                    break;
+                }
+                continue;
+            }
+            Some(Ok(responses)) => responses,
+        };
+        drop(prepare_span);
+        prepare_stragglers = Some(stragglers);
 
-        // Determine which value to propose.
-        let new_value = if let Some(accepted_proposal) = prepare_responses
+        // If a node in the quorum knows the chosen value, let every node know and stop.
+        if let Some(chosen_proposal) = prepare_responses
             .iter()
-            .filter_map(|response| response.accepted_proposal.clone())
-            .max_by_key(|accepted_proposal| accepted_proposal.0)
+            .find_map(|response| response.chosen_proposal.clone())
         {
+            debug!(
+                round = proposal_number.round,
+                proposer:% = proposal_number.proposer_address,
+                value = chosen_proposal.1;
+                "Quorum already knows the chosen value.",
+            );
+            round_span.set("outcome", "already chosen");
+            notify_chosen(node, endpoint_prefix, &chosen_proposal, &round_span).await;
+            return Ok(Some(chosen_proposal));
+        }
+        if let Some(chosen_proposal) = known_chosen_proposal().await {
+            round_span.set("outcome", "already chosen");
+            return Ok(Some(chosen_proposal));
+        }
+
+        // Determine which value to propose.
+        let new_value = if let Some(accepted_proposal) = select_proposal(&prepare_responses) {
             // There was an accepted proposal. Use that.
             debug!(
-                "Discovered existing value from cluster: {}",
-                accepted_proposal.1,
+                round = proposal_number.round,
+                proposer:% = proposal_number.proposer_address,
+                accepted_round = accepted_proposal.0.round,
+                accepted_proposer:% = accepted_proposal.0.proposer_address,
+                value = accepted_proposal.1;
+                "Discovered existing value from cluster.",
             );
             accepted_proposal.1
         } else {
             // Propose the given value, or break if there isn't one.
             if let Some(original_value) = original_value {
-                debug!("Quorum replied with no existing value.");
+                debug!(
+                    round = proposal_number.round,
+                    proposer:% = proposal_number.proposer_address;
+                    "Quorum replied with no existing value.",
+                );
                 original_value.to_owned()
             } else {
+                round_span.set("outcome", "nothing to propose");
                 break;
             }
         };
 
         // Send an accept message to all the nodes.
         debug!(
-            "Requesting acceptance of value `{}`.",
-            // The `unwrap` is safe because serialization should never fail.
-            yaml_serde::to_string(&proposal_number).unwrap(),
-        );
-        let accept_responses = broadcast_quorum::<AcceptResponse>(
-            &client,
-            nodes,
-            ACCEPT_ENDPOINT,
-            &AcceptRequest {
-                proposal: (proposal_number, new_value.clone()),
-            },
-        )
-        .await;
+            endpoint = accept_endpoint,
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address,
+            value = new_value;
+            "Requesting acceptance of value.",
+        );
+        let mut accept_span = round_span.child("accept phase", Kind::Internal);
+        accept_span.set("value", &new_value);
+        let (accept_responses, stragglers) = match shutdown
+            .interrupt(broadcast_quorum::<AcceptResponse>(
+                client,
+                nodes,
+                &accept_endpoint,
+                &AcceptRequest {
+                    proposal: (proposal_number.clone(), new_value.clone()),
+                },
+                backoff.peers(),
+                &accept_span,
+            ))
+            .await
+        {
+            None => {
+                round_span.set("outcome", "interrupted");
+                break;
+            }
+            Some(Err(error)) => {
+                debug!(
+                    round = proposal_number.round,
+                    proposer:% = proposal_number.proposer_address;
+                    "{error} Starting over.",
+                );
+                round_span.set("outcome", "unreachable");
+                drop(accept_span);
+                drop(round_span);
+                if rounds.lose(None, shutdown).await.is_none() {
+                    break;
+                }
+                continue;
+            }
+            Some(Ok(responses)) => responses,
+        };
+        drop(accept_span);
+        accept_stragglers = Some(stragglers);
+
+        // Determine if the proposed value was chosen, and which proposer got in the way if not.
+        let value_chosen = accept_responses
+            .iter()
+            .all(|response| response.min_proposal_number <= proposal_number && !response.leased);
+        let winner = winner(
+            &proposal_number,
+            accept_responses
+                .iter()
+                .map(|response| &response.min_proposal_number),
+        );
 
-        // Determine if the proposed value was chosen.
-        let mut value_chosen = true;
-        for response in accept_responses {
-            if response.min_proposal_number > proposal_number {
-                value_chosen = false;
+        // Update the `next_round`, if applicable.
+        skip_past(
+            &state,
+            data_file_path,
+            accept_responses
+                .iter()
+                .map(|response| &response.min_proposal_number),
+        )
+        .await?;
+        if value_chosen {
+            // The protocol succeeded. Notify all the nodes and return. Nodes from before the
+            // "chosen" endpoint existed are also sent the value alone, which is all they
+            // understand.
+            debug!(
+                endpoint = chosen_endpoint,
+                round = proposal_number.round,
+                proposer:% = proposal_number.proposer_address,
+                value = new_value;
+                "Consensus achieved. Notifying all the nodes.",
+            );
+            round_span.set("outcome", "chosen");
+            let chosen_proposal = (proposal_number, new_value);
+            if notify_chosen(node, endpoint_prefix, &chosen_proposal, &round_span)
+                .await
+                .is_some()
+            {
+                debug!("Proposer finished.");
+            } else {
+                debug!("Proposer interrupted before every node was notified of the chosen value.");
             }
+            return Ok(Some(chosen_proposal));
+        }
+
+        // The protocol failed. Back off before starting over, unless the proposer that got in the way
+        // already got its value chosen.
+        debug!(
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address;
+            "Failed to reach consensus. Starting over.",
+        );
+        round_span.set("outcome", "preempted");
+        drop(round_span);
+        if let Some(chosen_proposal) = known_chosen_proposal().await {
+            return Ok(Some(chosen_proposal));
+        }
+        if rounds.lose(winner.as_ref(), shutdown).await.is_none() {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+// Open a fast ballot, which lets clients send values directly to the nodes. This is only done if a
+// quorum promises to honor the ballot and none of them have accepted a value, since otherwise a
+// value may already have been chosen. If other proposers have since made higher promises, a higher
+// ballot is tried, up to a limit. Until a quorum can be reached at all, the prepare is retried
+// without counting against that limit.
+#[allow(clippy::too_many_lines)]
+pub async fn open_fast_ballot(node: &Node) -> Result<(), io::Error> {
+    let Node {
+        state,
+        data_file_path,
+        nodes,
+        index: node_index,
+        client,
+        backoff,
+        shutdown,
+        tracer,
+        ..
+    } = node;
+    let mut attempts = 0;
 
-            // Update the `next_round`, if applicable. The `unwrap` is safe
-            // since it can only fail if a panic already happened.
+    while attempts < FAST_ATTEMPTS {
+        // Generate a new proposal number for the ballot.
+        let proposal_number = {
+            // The `unwrap` is safe since it can only fail if a panic already happened.
             let mut guard = state.write().await;
-            if guard.0.next_round <= response.min_proposal_number.round {
-                guard.0.next_round = response.min_proposal_number.round + 1;
-                crate::state::write(&guard.0, data_file_path).await?;
+            let proposal_number = generate_proposal_number(nodes, *node_index, &mut guard.0);
+            crate::state::write(&guard.0, data_file_path).await?;
+            proposal_number
+        };
+
+        let mut span = tracer.span("fast ballot", Kind::Internal);
+        span.set("round", &proposal_number.round);
+        span.set("proposer", &proposal_number.proposer_address);
+
+        // Send a prepare message to all the nodes.
+        debug!(
+            endpoint = PREPARE_ENDPOINT,
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address;
+            "Preparing fast ballot.",
+        );
+        let prepare_responses = match shutdown
+            .interrupt(broadcast_quorum::<PrepareResponse>(
+                client,
+                nodes,
+                PREPARE_ENDPOINT,
+                &PrepareRequest {
+                    proposal_number: Some(proposal_number.clone()),
+                    lease_duration_ms: None,
+                },
+                backoff.peers(),
+                &span,
+            ))
+            .await
+        {
+            None => {
+                span.set("outcome", "interrupted");
+                return Ok(());
+            }
+            Some(Err(error)) => {
+                debug!(
+                    round = proposal_number.round,
+                    proposer:% = proposal_number.proposer_address;
+                    "{error} Trying to open a fast ballot again.",
+                );
+                span.set("outcome", "unreachable");
+                drop(span);
+                if shutdown
+                    .interrupt(sleep(backoff.fast_delay()))
+                    .await
+                    .is_none()
+                {
+                    return Ok(());
+                }
+                continue;
             }
+            Some(Ok((prepare_responses, _))) => prepare_responses,
+        };
+        attempts += 1;
+
+        if prepare_responses
+            .iter()
+            .any(|response| response.accepted_proposal.is_some())
+        {
+            info!("Not opening a fast ballot, since a value has already been accepted.");
+            span.set("outcome", "value already accepted");
+            return Ok(());
         }
-        if value_chosen {
-            // The protocol succeeded. Notify all the nodes and return.
-            debug!("Consensus achieved. Notifying all the nodes.");
-            try_to_broadcast::<ChooseResponse>(
-                &client,
+
+        // Open the ballot on every node which is reachable. Nodes which miss this message won't
+        // take part in the ballot, which may prevent a fast quorum from forming, but the classic
+        // rounds that follow will still choose a value.
+        debug!(
+            endpoint = OPEN_ENDPOINT,
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address;
+            "Opening fast ballot.",
+        );
+        let Some(open_responses) = shutdown
+            .interrupt(try_to_broadcast::<OpenResponse>(
+                client,
                 nodes,
-                CHOOSE_ENDPOINT,
-                &ChooseRequest { value: new_value },
-            )
-            .await;
-            debug!("Proposer finished.");
+                OPEN_ENDPOINT,
+                &OpenRequest {
+                    proposal_number: proposal_number.clone(),
+                },
+                backoff.peers(),
+                &span,
+            ))
+            .await
+        else {
+            span.set("outcome", "interrupted");
+            return Ok(());
+        };
+        let min_proposal_numbers = open_responses
+            .into_iter()
+            .filter_map(|response| Some(response.ok()?.min_proposal_number))
+            .collect::<Vec<_>>();
+        let open_count = min_proposal_numbers
+            .iter()
+            .filter(|min_proposal_number| **min_proposal_number == proposal_number)
+            .count();
+
+        if open_count >= fast_quorum_size(nodes.len()) {
+            info!(
+                round = proposal_number.round,
+                proposer:% = proposal_number.proposer_address;
+                "Opened a fast ballot on {open_count} of {} nodes.",
+                nodes.len(),
+            );
+            span.set("outcome", "opened");
             return Ok(());
         }
 
-        // The protocol failed. Sleep for a random duration before starting over.
-        debug!("Failed to reach consensus. Starting over.");
-        sleep(Duration::from_millis(
-            rand::rng()
-                .random_range(0..=MAX_RETRY_DELAY.as_millis())
-                .try_into()
-                .unwrap(), // Safe by manual inspection
-        ))
-        .await;
+        // Skip past any higher promises before trying again.
+        debug!(
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address;
+            "Opened a fast ballot on only {open_count} of {} nodes.",
+            nodes.len(),
+        );
+        span.set("outcome", "no fast quorum");
+        if let Some(highest_proposal_number) = min_proposal_numbers.iter().max() {
+            let mut guard = state.write().await;
+            if guard.0.next_round <= highest_proposal_number.round {
+                guard.0.next_round = highest_proposal_number.round + 1;
+                crate::state::write(&guard.0, data_file_path).await?;
+            }
+        }
+        drop(span);
+        if shutdown
+            .interrupt(sleep(backoff.fast_delay()))
+            .await
+            .is_none()
+        {
+            return Ok(());
+        }
     }
 
+    info!("Unable to open a fast ballot on a fast quorum. Classic rounds will be used instead.");
     Ok(())
 }
 
+// Send a value directly to the nodes in a fast ballot. If a fast quorum accepts the same proposal,
+// it's chosen, and the nodes are notified. Otherwise (e.g., because clients sent different values at
+// the same time, or no fast ballot is open), the caller should fall back to classic rounds, which
+// recover from such collisions. Returns the chosen proposal, if there is one.
+pub async fn propose_fast(node: &Node, value: &str) -> Option<(ProposalNumber, String)> {
+    let Node {
+        nodes,
+        client,
+        backoff,
+        shutdown,
+        tracer,
+        ..
+    } = node;
+    let mut span = tracer.span("fast round", Kind::Internal);
+    span.set("value", value);
+
+    for _ in 0..FAST_ATTEMPTS {
+        debug!(
+            endpoint = FAST_ACCEPT_ENDPOINT,
+            value = value;
+            "Sending value directly to the nodes.",
+        );
+        let responses = shutdown
+            .interrupt(try_to_broadcast::<FastAcceptResponse>(
+                client,
+                nodes,
+                FAST_ACCEPT_ENDPOINT,
+                &FastAcceptRequest {
+                    value: value.to_owned(),
+                },
+                backoff.peers(),
+                &span,
+            ))
+            .await?;
+        let accepted_proposals = responses
+            .into_iter()
+            .filter_map(|response| response.ok()?.accepted_proposal)
+            .collect::<Vec<_>>();
+
+        // Check if a fast quorum accepted the same proposal.
+        let mut counts = BTreeMap::<&(ProposalNumber, String), usize>::new();
+        for accepted_proposal in &accepted_proposals {
+            *counts.entry(accepted_proposal).or_default() += 1;
+        }
+        if let Some((chosen_proposal, _)) = counts
+            .into_iter()
+            .find(|(_, count)| *count >= fast_quorum_size(nodes.len()))
+        {
+            let chosen_proposal = chosen_proposal.clone();
+            span.set("outcome", "chosen");
+            notify_chosen(node, "", &chosen_proposal, &span).await;
+            return Some(chosen_proposal);
+        }
+
+        // Check if another value was accepted in the latest ballot, in which case there's no point in
+        // waiting for more nodes to accept this one.
+        if let Some(highest_proposal_number) = accepted_proposals
+            .iter()
+            .map(|(proposal_number, _)| proposal_number)
+            .max()
+            && accepted_proposals
+                .iter()
+                .any(|(proposal_number, other_value)| {
+                    proposal_number == highest_proposal_number && other_value != value
+                })
+        {
+            debug!(value = value; "Collision in fast ballot.");
+            span.set("outcome", "collision");
+            return None;
+        }
+
+        // Otherwise, the ballot may not be open on enough nodes yet.
+        shutdown.interrupt(sleep(backoff.fast_delay())).await?;
+    }
+
+    debug!(value = value; "No fast quorum accepted the value.");
+    span.set("outcome", "no fast quorum");
+    None
+}
+
+// Let all the nodes know that a proposal was chosen, either for the node's own instance or for a
+// named one, whose endpoints have the given prefix. Nodes from before the "chosen" endpoint existed
+// are also sent the value alone, which is all they understand. They don't host named instances.
+// Returns nothing if a shutdown was requested before every node was tried.
+async fn notify_chosen(
+    node: &Node,
+    endpoint_prefix: &str,
+    chosen_proposal: &(ProposalNumber, String),
+    parent: &Span,
+) -> Option<()> {
+    let span = parent.child("choose phase", Kind::Internal);
+    let chosen_endpoint = format!("{endpoint_prefix}{CHOSEN_ENDPOINT}");
+    let chosen_request = ChosenRequest {
+        proposal: chosen_proposal.clone(),
+    };
+    let choose_request = ChooseRequest {
+        value: chosen_proposal.1.clone(),
+    };
+    node.shutdown
+        .interrupt(async {
+            join!(
+                try_to_broadcast::<ChosenResponse>(
+                    &node.client,
+                    &node.nodes,
+                    &chosen_endpoint,
+                    &chosen_request,
+                    node.backoff.peers(),
+                    &span,
+                ),
+                async {
+                    if endpoint_prefix.is_empty() {
+                        try_to_broadcast::<ChooseResponse>(
+                            &node.client,
+                            &node.nodes,
+                            CHOOSE_ENDPOINT,
+                            &choose_request,
+                            node.backoff.peers(),
+                            &span,
+                        )
+                        .await;
+                    }
+                },
+            );
+        })
+        .await
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{proposer::generate_proposal_number, state::initial};
+    use crate::{
+        acceptor::PrepareResponse,
+        address::Address,
+        proposer::{
+            QuorumRead, fast_quorum_size, generate_proposal_number, interpret_quorum_read,
+            select_proposal,
+        },
+        state::{ProposalNumber, initial},
+    };
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
+    fn accepted(round: u64, value: &str) -> PrepareResponse {
+        PrepareResponse {
+            accepted_proposal: Some((
+                ProposalNumber {
+                    round,
+                    proposer_address: Address::Tcp(SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        3000,
+                    )),
+                },
+                value.to_owned(),
+            )),
+            chosen_proposal: None,
+            lease: None,
+        }
+    }
+
     #[test]
     fn first_proposal_number() {
         let mut state = initial();
-        let address0 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)), 3000);
-        let address1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 3001);
-        let address2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3)), 3002);
-        let nodes = vec![address0, address1, address2];
+        let address0 = Address::Tcp(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            3000,
+        ));
+        let address1 = Address::Tcp(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+            3001,
+        ));
+        let address2 = Address::Tcp(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3)),
+            3002,
+        ));
+        let nodes = vec![address0, address1.clone(), address2];
         let pn = generate_proposal_number(&nodes, 1, &mut state.0);
         assert_eq!(pn.round, 0);
         assert_eq!(pn.proposer_address, address1);
@@ -166,9 +816,100 @@ mod tests {
     #[test]
     fn second_proposal_number() {
         let mut state = initial();
-        let nodes = vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)];
+        let nodes = vec![Address::Tcp(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            3000,
+        ))];
         let pn0 = generate_proposal_number(&nodes, 0, &mut state.0);
         let pn1 = generate_proposal_number(&nodes, 0, &mut state.0);
         assert!(pn1 > pn0);
     }
+
+    #[test]
+    fn fast_quorum_sizes() {
+        assert_eq!(fast_quorum_size(1), 1);
+        assert_eq!(fast_quorum_size(3), 3);
+        assert_eq!(fast_quorum_size(4), 3);
+        assert_eq!(fast_quorum_size(5), 4);
+        assert_eq!(fast_quorum_size(7), 6);
+    }
+
+    #[test]
+    fn select_no_proposal() {
+        assert_eq!(
+            select_proposal(&[
+                PrepareResponse {
+                    accepted_proposal: None,
+                    chosen_proposal: None,
+                    lease: None,
+                },
+                PrepareResponse {
+                    accepted_proposal: None,
+                    chosen_proposal: None,
+                    lease: None,
+                },
+            ]),
+            None,
+        );
+    }
+
+    #[test]
+    fn select_highest_proposal() {
+        assert_eq!(
+            select_proposal(&[accepted(1, "foo"), accepted(2, "bar")]).map(|proposal| proposal.1),
+            Some("bar".to_owned()),
+        );
+    }
+
+    #[test]
+    fn select_most_common_value_in_fast_ballot() {
+        assert_eq!(
+            select_proposal(&[
+                accepted(2, "foo"),
+                accepted(2, "bar"),
+                accepted(2, "bar"),
+                accepted(1, "baz"),
+            ])
+            .map(|proposal| proposal.1),
+            Some("bar".to_owned()),
+        );
+    }
+
+    #[test]
+    fn quorum_read_outcomes() {
+        let nothing = || PrepareResponse {
+            accepted_proposal: None,
+            chosen_proposal: None,
+            lease: None,
+        };
+
+        assert_eq!(
+            interpret_quorum_read(&[nothing(), nothing()], false),
+            QuorumRead::NothingChosen,
+        );
+        assert_eq!(
+            interpret_quorum_read(&[accepted(1, "foo"), accepted(1, "foo")], false),
+            QuorumRead::Chosen(accepted(1, "foo").accepted_proposal.unwrap()),
+        );
+        assert_eq!(
+            interpret_quorum_read(&[accepted(1, "foo"), accepted(1, "foo")], true),
+            QuorumRead::Unknown,
+        );
+        assert_eq!(
+            interpret_quorum_read(&[accepted(1, "foo"), accepted(2, "foo")], false),
+            QuorumRead::Unknown,
+        );
+        assert_eq!(
+            interpret_quorum_read(&[nothing(), accepted(1, "foo")], false),
+            QuorumRead::Unknown,
+        );
+
+        // A node which knows the chosen value settles the question.
+        let mut chosen = accepted(1, "foo");
+        chosen.chosen_proposal.clone_from(&chosen.accepted_proposal);
+        assert_eq!(
+            interpret_quorum_read(&[nothing(), chosen.clone()], true),
+            QuorumRead::Chosen(chosen.accepted_proposal.unwrap()),
+        );
+    }
 }
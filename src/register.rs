@@ -0,0 +1,594 @@
+use crate::{
+    acceptor::{
+        ACCEPT_ENDPOINT, AcceptRequest, AcceptResponse, LeaseStatus, PREPARE_ENDPOINT,
+        PrepareRequest, PrepareResponse,
+    },
+    backoff::winner,
+    config,
+    node::Node,
+    proposer::{generate_proposal_number, select_proposal, skip_past},
+    rpc::{Stragglers, broadcast_quorum},
+    state::{self, ProposalNumber},
+    trace::Kind,
+};
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+// The endpoint clients use to read and change the register
+pub const REGISTER_ENDPOINT: &str = "/register";
+
+// The contents of the register. The version is incremented by every change, which lets clients make
+// changes conditional on nothing else having changed the register since they read it.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Register {
+    pub version: u64,
+    pub value: Option<String>,
+}
+
+// How many of the most recent changes are remembered
+const RECENT_CHANGES: usize = 100;
+
+// The value of each proposal: the register, along with the IDs of the changes which most recently
+// took effect. A round can be preempted after some nodes accepted its proposal, and a later round
+// may then build on that proposal. The IDs let the proposer tell that its change already took
+// effect when it retries, so the change isn't made twice.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Stored {
+    #[serde(flatten)]
+    register: Register,
+    recent_changes: VecDeque<u64>,
+}
+
+// A change a client wants to make to the register
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Change {
+    // Leave the register as it is.
+    Read,
+
+    // Replace the value.
+    Set { value: String },
+
+    // Replace the value if the register is at the given version.
+    Cas { version: u64, value: String },
+
+    // Add to the value, which must be an integer (or missing, which counts as zero).
+    Increment { by: i64 },
+}
+
+impl Change {
+    // Compute the new contents of the register, or explain why the change can't be made.
+    pub fn apply(&self, register: &Register) -> Result<Register, String> {
+        let value = match self {
+            Self::Read => return Ok(register.clone()),
+            Self::Set { value } => value.clone(),
+            Self::Cas { version, value } => {
+                if *version != register.version {
+                    return Err(format!(
+                        "The register is at version {}, not {version}.",
+                        register.version,
+                    ));
+                }
+                value.clone()
+            }
+            Self::Increment { by } => {
+                let current = match &register.value {
+                    Some(value) => value.parse::<i64>().map_err(|error| {
+                        format!("The value `{value}` is not an integer. Reason: {error}")
+                    })?,
+                    None => 0,
+                };
+                current
+                    .checked_add(*by)
+                    .ok_or_else(|| format!("Adding {by} to {current} would overflow."))?
+                    .to_string()
+            }
+        };
+
+        Ok(Register {
+            version: register.version + 1,
+            value: Some(value),
+        })
+    }
+}
+
+// A leader lease on the register. While it lasts, the nodes which granted it refuse proposals from
+// every other node, so only this node can change the register, and it can answer reads without
+// running a round.
+#[derive(Clone)]
+pub struct LeaderLease {
+    duration: Duration,
+    max_clock_drift_ppm: u64,
+    held: Arc<Mutex<Option<HeldLease>>>,
+}
+
+// What the node knows while it holds a leader lease
+struct HeldLease {
+    expires_at: Instant,
+    proposal_number: ProposalNumber,
+    register: Register,
+}
+
+impl LeaderLease {
+    pub fn new(config: config::LeaderLease) -> Self {
+        Self {
+            duration: Duration::from_millis(config.duration_ms),
+            max_clock_drift_ppm: config.max_clock_drift_ppm,
+            held: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // How long the nodes which grant a lease refuse other proposals, by their own clocks
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    // How long the holder can rely on a lease, by its own clock, from when it asked for it. The
+    // nodes which granted the lease start timing it later, but their clocks may run slower than the
+    // holder's, and the holder's clock may run faster than real time, by the maximum drift.
+    fn trusted_duration(&self) -> Duration {
+        // The `as` conversion is fine since a drift of millions of parts per million is absurd.
+        #[allow(clippy::cast_precision_loss)]
+        let drift = 1.0_f64 + self.max_clock_drift_ppm as f64 / 1_000_000.0_f64;
+        self.duration.div_f64(drift * drift)
+    }
+
+    // Read the register, if the node holds the lease.
+    pub fn read(&self) -> Option<Register> {
+        // The `unwrap` is safe since the lock is never held across a panic.
+        let held = self.held.lock().unwrap();
+        held.as_ref()
+            .filter(|held| Instant::now() < held.expires_at)
+            .map(|held| held.register.clone())
+    }
+
+    // Record a round which succeeded, and which was started at the given time. If every node in the
+    // quorum granted the lease, it's extended. Either way, the register is updated unless a later
+    // round already did that.
+    fn record(
+        &self,
+        started_at: Instant,
+        granted: bool,
+        proposal_number: &ProposalNumber,
+        register: &Register,
+    ) {
+        // The `unwrap` is safe since the lock is never held across a panic.
+        let mut held = self.held.lock().unwrap();
+        let expires_at = granted.then(|| started_at + self.trusted_duration());
+        match &mut *held {
+            Some(held) => {
+                if let Some(expires_at) = expires_at {
+                    held.expires_at = held.expires_at.max(expires_at);
+                }
+                if *proposal_number > held.proposal_number {
+                    held.proposal_number = proposal_number.clone();
+                    held.register = register.clone();
+                }
+            }
+            None => {
+                if let Some(expires_at) = expires_at {
+                    *held = Some(HeldLease {
+                        expires_at,
+                        proposal_number: proposal_number.clone(),
+                        register: register.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Determine what to propose in a round which makes the change with the given ID, given the latest
+// proposal. Also returns the reason the change can't be made, if applicable.
+fn next(
+    stored: Stored,
+    apply: impl FnOnce(&Register) -> Result<Register, String>,
+    change_id: u64,
+) -> (Stored, Option<String>) {
+    if stored.recent_changes.contains(&change_id) {
+        return (stored, None);
+    }
+
+    match apply(&stored.register) {
+        Ok(register) if register == stored.register => (stored, None),
+        Ok(register) => {
+            let mut recent_changes = stored.recent_changes;
+            recent_changes.push_back(change_id);
+            if recent_changes.len() > RECENT_CHANGES {
+                recent_changes.pop_front();
+            }
+            (
+                Stored {
+                    register,
+                    recent_changes,
+                },
+                None,
+            )
+        }
+        Err(reason) => (stored, Some(reason)),
+    }
+}
+
+// Make a change to a register, CASPaxos style. Each round is a classic Paxos round in which the
+// proposer reads the latest register from a quorum in the prepare phase, applies the change to it,
+// and proposes the result in the accept phase. The acceptors don't need to know anything about
+// registers, since the register is just the value of the proposals. A change which can't be made
+// still proposes the latest register unchanged (as a read does), so that whatever is reported to the
+// client is known to have been accepted by a quorum. The change is given the number of the proposal
+// it will be part of, and it may be applied more than once if rounds are preempted. The register is
+// the node's own instance or a named one, whose endpoints have the given prefix. If a leader lease
+// is given, every round asks for it. Returns the resulting register and, if the change was rejected,
+// the reason why.
+#[allow(clippy::too_many_lines)]
+pub async fn change(
+    node: &Node,
+    state: Arc<RwLock<(state::Durable, state::Volatile)>>,
+    data_file_path: &Path,
+    endpoint_prefix: &str,
+    change: impl Fn(&Register, &ProposalNumber) -> Result<Register, String>,
+    leader_lease: Option<&LeaderLease>,
+) -> io::Result<(Register, Option<String>)> {
+    let Node {
+        nodes,
+        index: node_index,
+        client,
+        backoff,
+        shutdown,
+        tracer,
+        ..
+    } = node;
+    let interrupted = || io::Error::other("The node is shutting down.");
+    let prepare_endpoint = format!("{endpoint_prefix}{PREPARE_ENDPOINT}");
+    let accept_endpoint = format!("{endpoint_prefix}{ACCEPT_ENDPOINT}");
+    let change_id = rand::rng().random::<u64>();
+    let mut rounds = backoff.rounds(node.address());
+
+    // The nodes which didn't make it into the accept quorum of the previous round
+    let mut accept_stragglers = None::<Stragglers<AcceptResponse>>;
+
+    loop {
+        // Skip past any higher promises the stragglers made after the previous round moved on
+        // without them.
+        if let Some(stragglers) = &mut accept_stragglers {
+            skip_past(
+                &state,
+                data_file_path,
+                stragglers
+                    .late_responses()
+                    .iter()
+                    .map(|response| &response.min_proposal_number),
+            )
+            .await?;
+        }
+
+        // Generate a new proposal number.
+        let proposal_number = {
+            let mut guard = state.write().await;
+            let proposal_number = generate_proposal_number(nodes, *node_index, &mut guard.0);
+            crate::state::write(&guard.0, data_file_path).await?;
+            proposal_number
+        };
+        rounds.start();
+
+        let mut round_span = tracer.span("register round", Kind::Internal);
+        round_span.set("round", &proposal_number.round);
+        round_span.set("proposer", &proposal_number.proposer_address);
+        if !endpoint_prefix.is_empty() {
+            round_span.set("instance", endpoint_prefix);
+        }
+
+        // Read the latest register from a quorum.
+        let started_at = Instant::now();
+        let prepare_span = round_span.child("prepare phase", Kind::Internal);
+        let (prepare_responses, _) = match shutdown
+            .interrupt(broadcast_quorum::<PrepareResponse>(
+                client,
+                nodes,
+                &prepare_endpoint,
+                &PrepareRequest {
+                    proposal_number: Some(proposal_number.clone()),
+                    lease_duration_ms: leader_lease.map(|leader_lease| {
+                        u64::try_from(leader_lease.duration().as_millis()).unwrap_or(u64::MAX)
+                    }),
+                },
+                backoff.peers(),
+                &prepare_span,
+            ))
+            .await
+            .ok_or_else(interrupted)?
+        {
+            Ok(responses) => responses,
+            Err(error) => {
+                debug!(
+                    round = proposal_number.round,
+                    proposer:% = proposal_number.proposer_address;
+                    "{error} Starting over.",
+                );
+                round_span.set("outcome", "unreachable");
+                drop(prepare_span);
+                drop(round_span);
+                rounds.lose(None, shutdown).await.ok_or_else(interrupted)?;
+                continue;
+            }
+        };
+        drop(prepare_span);
+
+        // Back off if another node holds a leader lease.
+        if prepare_responses
+            .iter()
+            .any(|response| response.lease == Some(LeaseStatus::Refused))
+        {
+            debug!(
+                round = proposal_number.round,
+                proposer:% = proposal_number.proposer_address;
+                "Another node holds a leader lease. Starting over.",
+            );
+            round_span.set("outcome", "leased");
+            drop(round_span);
+            rounds.lose(None, shutdown).await.ok_or_else(interrupted)?;
+            continue;
+        }
+        let granted = prepare_responses
+            .iter()
+            .all(|response| response.lease == Some(LeaseStatus::Granted));
+        let stored = match select_proposal(&prepare_responses) {
+            Some((_, value)) => serde_json::from_str::<Stored>(&value).map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The accepted value `{value}` is not a register. Reason: {error}"),
+                )
+            })?,
+            None => Stored::default(),
+        };
+
+        // Apply the change.
+        let (new_stored, rejection) = next(
+            stored,
+            |register| change(register, &proposal_number),
+            change_id,
+        );
+        debug!(
+            endpoint = accept_endpoint,
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address,
+            version = new_stored.register.version,
+            value = new_stored.register.value;
+            "Proposing register.",
+        );
+
+        // Propose the new register. The `unwrap` is safe because serialization should never fail.
+        let accept_span = round_span.child("accept phase", Kind::Internal);
+        let (accept_responses, stragglers) = match shutdown
+            .interrupt(broadcast_quorum::<AcceptResponse>(
+                client,
+                nodes,
+                &accept_endpoint,
+                &AcceptRequest {
+                    proposal: (
+                        proposal_number.clone(),
+                        serde_json::to_string(&new_stored).unwrap(),
+                    ),
+                },
+                backoff.peers(),
+                &accept_span,
+            ))
+            .await
+            .ok_or_else(interrupted)?
+        {
+            Ok(responses) => responses,
+            Err(error) => {
+                debug!(
+                    round = proposal_number.round,
+                    proposer:% = proposal_number.proposer_address;
+                    "{error} Starting over.",
+                );
+                round_span.set("outcome", "unreachable");
+                drop(accept_span);
+                drop(round_span);
+                rounds.lose(None, shutdown).await.ok_or_else(interrupted)?;
+                continue;
+            }
+        };
+        drop(accept_span);
+        accept_stragglers = Some(stragglers);
+
+        // Check whether the proposal was accepted, and skip past any higher rounds if not.
+        let accepted = accept_responses
+            .iter()
+            .all(|response| response.min_proposal_number <= proposal_number && !response.leased);
+        let winner = winner(
+            &proposal_number,
+            accept_responses
+                .iter()
+                .map(|response| &response.min_proposal_number),
+        );
+        skip_past(
+            &state,
+            data_file_path,
+            accept_responses
+                .iter()
+                .map(|response| &response.min_proposal_number),
+        )
+        .await?;
+        if accepted {
+            if let Some(leader_lease) = leader_lease {
+                leader_lease.record(started_at, granted, &proposal_number, &new_stored.register);
+            }
+            round_span.set(
+                "outcome",
+                if rejection.is_some() {
+                    "rejected"
+                } else {
+                    "accepted"
+                },
+            );
+            return Ok((new_stored.register, rejection));
+        }
+
+        // Another proposer got in the way. Back off before starting over.
+        debug!(
+            round = proposal_number.round,
+            proposer:% = proposal_number.proposer_address;
+            "Register round was preempted. Starting over.",
+        );
+        round_span.set("outcome", "preempted");
+        drop(round_span);
+        rounds
+            .lose(winner.as_ref(), shutdown)
+            .await
+            .ok_or_else(interrupted)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        config,
+        register::{Change, LeaderLease, Register, Stored, next},
+        state::ProposalNumber,
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn read_leaves_register_unchanged() {
+        let register = Register {
+            version: 3,
+            value: Some("foo".to_owned()),
+        };
+        assert_eq!(Change::Read.apply(&register), Ok(register));
+    }
+
+    #[test]
+    fn set_bumps_version() {
+        assert_eq!(
+            Change::Set {
+                value: "foo".to_owned(),
+            }
+            .apply(&Register::default()),
+            Ok(Register {
+                version: 1,
+                value: Some("foo".to_owned()),
+            }),
+        );
+    }
+
+    #[test]
+    fn cas_checks_version() {
+        let register = Register {
+            version: 2,
+            value: Some("foo".to_owned()),
+        };
+        assert!(
+            Change::Cas {
+                version: 1,
+                value: "bar".to_owned(),
+            }
+            .apply(&register)
+            .is_err(),
+        );
+        assert_eq!(
+            Change::Cas {
+                version: 2,
+                value: "bar".to_owned(),
+            }
+            .apply(&register),
+            Ok(Register {
+                version: 3,
+                value: Some("bar".to_owned()),
+            }),
+        );
+    }
+
+    #[test]
+    fn increment_adds_to_integer() {
+        let register = Change::Increment { by: 2 }
+            .apply(&Register::default())
+            .unwrap();
+        assert_eq!(register.value, Some("2".to_owned()));
+        let register = Change::Increment { by: -5 }.apply(&register).unwrap();
+        assert_eq!(register.value, Some("-3".to_owned()));
+    }
+
+    #[test]
+    fn increment_rejects_non_integer() {
+        let register = Register {
+            version: 1,
+            value: Some("foo".to_owned()),
+        };
+        assert!(Change::Increment { by: 1 }.apply(&register).is_err());
+    }
+
+    #[test]
+    fn parse_change() {
+        assert!(matches!(
+            serde_json::from_str::<Change>(r#"{"op":"cas","version":1,"value":"foo"}"#).unwrap(),
+            Change::Cas { version: 1, .. },
+        ));
+        assert!(serde_json::from_str::<Change>(r#"{"op":"delete"}"#).is_err());
+    }
+
+    #[test]
+    fn change_takes_effect_once() {
+        let change = Change::Increment { by: 1 };
+        let (stored, rejection) = next(Stored::default(), |register| change.apply(register), 42);
+        assert_eq!(rejection, None);
+        assert_eq!(stored.register.value, Some("1".to_owned()));
+
+        let (stored, _) = next(stored, |register| change.apply(register), 43);
+        let (stored, _) = next(stored, |register| change.apply(register), 42);
+        assert_eq!(stored.register.value, Some("2".to_owned()));
+    }
+
+    #[test]
+    fn leader_lease_allows_for_clock_drift() {
+        let leader_lease = LeaderLease::new(config::LeaderLease {
+            duration_ms: 10_000,
+            max_clock_drift_ppm: 10_000,
+        });
+        let trusted_duration = leader_lease.trusted_duration();
+        assert!(trusted_duration < Duration::from_millis(9_804));
+        assert!(trusted_duration > Duration::from_millis(9_800));
+    }
+
+    #[test]
+    fn leader_lease_keeps_latest_register() {
+        let leader_lease = LeaderLease::new(config::LeaderLease {
+            duration_ms: 60_000,
+            max_clock_drift_ppm: 0,
+        });
+        let proposal_number = |round| ProposalNumber {
+            round,
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3000)),
+        };
+        let register = |version| Register {
+            version,
+            value: Some(version.to_string()),
+        };
+
+        // A round which didn't get the lease doesn't grant one.
+        leader_lease.record(Instant::now(), false, &proposal_number(0), &register(1));
+        assert_eq!(leader_lease.read(), None);
+
+        leader_lease.record(Instant::now(), true, &proposal_number(2), &register(3));
+        assert_eq!(leader_lease.read(), Some(register(3)));
+
+        // A round which finished late doesn't roll the register back.
+        leader_lease.record(Instant::now(), true, &proposal_number(1), &register(2));
+        assert_eq!(leader_lease.read(), Some(register(3)));
+        leader_lease.record(Instant::now(), false, &proposal_number(3), &register(4));
+        assert_eq!(leader_lease.read(), Some(register(4)));
+    }
+}
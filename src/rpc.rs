@@ -1,39 +1,103 @@
+use crate::{
+    address::Address,
+    peers::Peers,
+    trace::{Kind, Span, SpanRef, TRACEPARENT_HEADER},
+    transport::{Connector, uri},
+};
 use bytes::Bytes;
 use futures::{StreamExt, stream::FuturesUnordered};
 use http_body_util::{BodyExt, Full};
-use hyper::{Method, Request};
+use hyper::{Method, Request, Response, body::Incoming, header::HeaderValue};
 use hyper_util::{
-    client::legacy::{Client, connect::HttpConnector},
-    rt::TokioExecutor,
+    client::legacy::Client,
+    rt::{TokioExecutor, TokioTimer},
 };
 use serde::{Serialize, de::DeserializeOwned};
-use std::{cmp::min, io, net::SocketAddr};
-use tokio::time::{Duration, sleep};
+use std::{
+    cmp::min,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinSet,
+    time::{Duration, sleep, timeout},
+};
 
 // Duration constants
 const EXPONENTIAL_BACKOFF_MIN: Duration = Duration::from_millis(50);
 const EXPONENTIAL_BACKOFF_MAX: Duration = Duration::from_secs(1);
 const EXPONENTIAL_BACKOFF_MULTIPLIER: u32 = 2;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
 
-pub type HttpClient = Client<HttpConnector, Full<Bytes>>;
+// How many idle connections to keep open to each node. Proposers, stragglers, and the acceptor can
+// all have requests in flight to the same node at once.
+const POOL_MAX_IDLE_PER_HOST: usize = 32;
 
-// Create an HTTP client for Paxos RPC requests.
-pub fn new_client() -> HttpClient {
-    Client::builder(TokioExecutor::new()).build(HttpConnector::new())
+// The HTTP header which identifies the node that sent a request, so tools like the chaos proxy can
+// tell the nodes apart
+pub const SENDER_HEADER: &str = "paxos-sender";
+
+pub type HttpClient = Client<Connector, Full<Bytes>>;
+
+// Create an HTTP client for Paxos RPC requests. Idle connections are kept open for a while so later
+// requests can reuse them. With HTTP/2, the requests to each node are multiplexed over a single
+// connection instead.
+pub fn new_client(connector: Connector, http2: bool) -> HttpClient {
+    Client::builder(TokioExecutor::new())
+        .pool_timer(TokioTimer::new())
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .http2_only(http2)
+        .build(connector)
 }
 
-// Send a request without retries.
+// Send a request without retries, recording it in a child of the given span and recording whether
+// it succeeded in the health of the node.
 async fn try_to_send<T: DeserializeOwned>(
     client: &HttpClient,
-    node: SocketAddr,
+    peers: &Peers,
+    node: &Address,
     endpoint: &str,
     payload: &impl Serialize,
+    parent: &SpanRef,
 ) -> io::Result<T> {
+    let mut span = parent.child(format!("POST {endpoint}"), Kind::Client);
+    span.set("node", node);
+    let result = try_to_send_in_span(client, peers, node, endpoint, payload, &span).await;
+    peers.record(node, result.is_ok());
+    if let Err(error) = &result {
+        span.fail(error);
+    }
+    result
+}
+
+// Send a request without retries, propagating the trace context of the given span.
+async fn try_to_send_in_span<T: DeserializeOwned>(
+    client: &HttpClient,
+    peers: &Peers,
+    node: &Address,
+    endpoint: &str,
+    payload: &impl Serialize,
+    span: &Span,
+) -> io::Result<T> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(uri(node, endpoint));
+    // Socket paths might not be valid header values, in which case the sender goes unidentified.
+    if let Ok(sender) = HeaderValue::try_from(peers.address().to_string()) {
+        request = request.header(SENDER_HEADER, sender);
+    }
+    if let Some(traceparent) = span.traceparent() {
+        request = request.header(TRACEPARENT_HEADER, traceparent);
+    }
+
     let response = client
         .request(
-            Request::builder()
-                .method(Method::POST)
-                .uri(format!("http://{node}{endpoint}"))
+            request
                 // The `unwrap` is safe because serialization should never fail.
                 .body(Full::new(Bytes::from(serde_json::to_vec(payload).unwrap())))
                 .unwrap(), // Safe since we constructed a well-formed request
@@ -41,6 +105,31 @@ async fn try_to_send<T: DeserializeOwned>(
         .await
         .map_err(|error| io::Error::other(format!("Unable to send request. Reason: {error}")))?;
 
+    parse_response(response).await
+}
+
+// Fetch a resource from a node without retries.
+pub async fn get<T: DeserializeOwned>(
+    client: &HttpClient,
+    node: &Address,
+    endpoint: &str,
+) -> io::Result<T> {
+    let response = client
+        .request(
+            Request::builder()
+                .method(Method::GET)
+                .uri(uri(node, endpoint))
+                .body(Full::new(Bytes::new()))
+                .unwrap(), // Safe since we constructed a well-formed request
+        )
+        .await
+        .map_err(|error| io::Error::other(format!("Unable to send request. Reason: {error}")))?;
+
+    parse_response(response).await
+}
+
+// Read and parse the JSON body of a response.
+async fn parse_response<T: DeserializeOwned>(response: Response<Incoming>) -> io::Result<T> {
     let body = response
         .into_body()
         .collect()
@@ -58,29 +147,51 @@ async fn try_to_send<T: DeserializeOwned>(
     })
 }
 
-// Send a request, retrying with exponential backoff until it succeeds.
+// Take a retry from a budget, unless it's used up.
+fn spend(retry_budget: &AtomicU32) -> bool {
+    retry_budget
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retries| {
+            retries.checked_sub(1)
+        })
+        .is_ok()
+}
+
+// Send a request, retrying with exponential backoff until it succeeds. Gives up (returning nothing)
+// if the node is considered down or the retry budget is used up.
 async fn send<T: DeserializeOwned>(
     client: &HttpClient,
-    node: SocketAddr,
+    peers: &Peers,
+    retry_budget: &AtomicU32,
+    node: &Address,
     endpoint: &str,
     payload: &impl Serialize,
-) -> T {
+    parent: &SpanRef,
+) -> Option<T> {
     // The delay between requests
     let mut delay = EXPONENTIAL_BACKOFF_MIN;
 
     // Retry until the request succeeds.
     loop {
+        // Skip nodes which are considered down, unless it's time to probe them.
+        if !peers.allow(node) {
+            return None;
+        }
+
         // Send the request.
-        match try_to_send(client, node, endpoint, payload).await {
+        match try_to_send(client, peers, node, endpoint, payload, parent).await {
             Ok(response) => {
-                return response;
+                return Some(response);
             }
             Err(error) => {
                 // Log the error.
-                debug!("Received error: {error}");
+                debug!(endpoint = endpoint, node:% = node; "Received error: {error}");
             }
         }
 
+        if !spend(retry_budget) {
+            return None;
+        }
+
         // Sleep before retrying.
         sleep(delay).await;
         delay = min(
@@ -90,33 +201,284 @@ async fn send<T: DeserializeOwned>(
     }
 }
 
-// Send a request to all nodes without retries. Return once all responses come in.
+// Send a request to all nodes without retries, skipping the ones which are considered down. Return
+// once all responses come in.
 pub async fn try_to_broadcast<T: DeserializeOwned>(
     client: &HttpClient,
-    nodes: &[SocketAddr],
+    nodes: &[Address],
     endpoint: &str,
     payload: &impl Serialize,
+    peers: &Peers,
+    parent: &Span,
 ) -> Vec<Result<T, io::Error>> {
+    let parent = &parent.reference();
     nodes
         .iter()
-        .map(|node| try_to_send(client, *node, endpoint, payload))
+        .map(|node| async move {
+            if peers.allow(node) {
+                try_to_send(client, peers, node, endpoint, payload, parent).await
+            } else {
+                Err(io::Error::other(format!("Node {node} is considered down.")))
+            }
+        })
         .collect::<FuturesUnordered<_>>()
         .collect()
         .await
 }
 
-// Send a request to all nodes with retries. Return once a majority of responses come in.
-pub async fn broadcast_quorum<T: DeserializeOwned>(
+// The responses from the nodes which didn't make it into a quorum, as they come in
+pub struct Stragglers<T> {
+    receiver: mpsc::UnboundedReceiver<Option<T>>,
+}
+
+impl<T> Stragglers<T> {
+    // Take the responses which have come in since the last time.
+    pub fn late_responses(&mut self) -> Vec<T> {
+        let mut responses = vec![];
+        while let Ok(response) = self.receiver.try_recv() {
+            responses.extend(response);
+        }
+        responses
+    }
+}
+
+// Send a request to all nodes with retries. Return once a majority of responses come in, or fail
+// once too many nodes have been given up on (because they're considered down or the retry budget is
+// used up) for that to happen. Rather than being abandoned, the requests to the other nodes keep
+// going in the background for a while, so those nodes still hear about the request, and their
+// responses can be taken from the returned stragglers.
+pub async fn broadcast_quorum<T: DeserializeOwned + Send + 'static>(
     client: &HttpClient,
-    nodes: &[SocketAddr],
+    nodes: &[Address],
     endpoint: &str,
     payload: &impl Serialize,
-) -> Vec<T> {
-    nodes
-        .iter()
-        .map(|node| send(client, *node, endpoint, payload))
-        .collect::<FuturesUnordered<_>>()
-        .take(nodes.len() / 2 + 1)
-        .collect()
+    peers: &Peers,
+    parent: &Span,
+) -> io::Result<(Vec<T>, Stragglers<T>)> {
+    // The `unwrap` is safe because serialization should never fail.
+    let payload = serde_json::to_value(payload).unwrap();
+    let retry_budget = Arc::new(AtomicU32::new(peers.retry_budget()));
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut requests = JoinSet::new();
+    for node in nodes {
+        let client = client.clone();
+        let peers = peers.clone();
+        let retry_budget = retry_budget.clone();
+        let node = node.clone();
+        let endpoint = endpoint.to_owned();
+        let payload = payload.clone();
+        let parent = parent.reference();
+        let sender = sender.clone();
+        requests.spawn(async move {
+            // Sending only fails if the stragglers were dropped, in which case nobody is listening.
+            let _ = sender.send(
+                send(
+                    &client,
+                    &peers,
+                    &retry_budget,
+                    &node,
+                    &endpoint,
+                    &payload,
+                    &parent,
+                )
+                .await,
+            );
+        });
+    }
+    drop(sender);
+
+    // Wait for a quorum, unless too many nodes are given up on. Every request ends up sending
+    // something unless its task panics.
+    let quorum_size = nodes.len() / 2 + 1;
+    let unreachable = || io::Error::other("Unable to reach a quorum of the nodes.");
+    let mut responses = vec![];
+    let mut given_up = 0;
+    while responses.len() < quorum_size {
+        if let Some(response) = receiver.recv().await.ok_or_else(unreachable)? {
+            responses.push(response);
+        } else {
+            given_up += 1;
+            if nodes.len() - given_up < quorum_size {
+                return Err(unreachable());
+            }
+        }
+    }
+
+    // Let the stragglers finish in the background. Dropping the requests aborts whichever ones are
+    // still going once the linger period is over.
+    let linger = peers.straggler_linger();
+    tokio::spawn(async move {
+        let _ = timeout(linger, async {
+            while requests.join_next().await.is_some() {}
+        })
+        .await;
+    });
+
+    Ok((responses, Stragglers { receiver }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        address::Address,
+        peers::{Peers, Policy},
+        rpc::{broadcast_quorum, new_client},
+        trace::{Kind, Tracer},
+        transport::Connector,
+    };
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::{Response, service::service_fn};
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto,
+    };
+    use std::{convert::Infallible, sync::atomic::Ordering, time::Duration};
+    use tokio::{net::TcpListener, time::sleep};
+
+    // Start a server which responds to every request with the number of milliseconds it waits
+    // before responding.
+    async fn serve(delay: Duration) -> Address {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address::Tcp(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(
+                            TokioIo::new(stream),
+                            service_fn(move |_| async move {
+                                sleep(delay).await;
+                                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(
+                                    delay.as_millis().to_string(),
+                                ))))
+                            }),
+                        )
+                        .await
+                });
+            }
+        });
+        address
+    }
+
+    // Find an address which nothing is listening on.
+    async fn unreachable() -> Address {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Address::Tcp(listener.local_addr().unwrap())
+    }
+
+    fn peers(nodes: &[Address], http2: bool, straggler_linger: Duration) -> Peers {
+        Peers::new(
+            &nodes[0],
+            nodes,
+            Policy {
+                http2,
+                straggler_linger,
+                retry_budget: 2,
+                probe_interval: Duration::from_mins(1),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn stragglers_respond_until_linger_is_over() {
+        let nodes = [
+            serve(Duration::ZERO).await,
+            serve(Duration::ZERO).await,
+            serve(Duration::from_millis(200)).await,
+        ];
+        let client = new_client(Connector::default(), false);
+        let tracer = Tracer::new(None, None, &nodes[0]).await.unwrap();
+        let span = tracer.span("test", Kind::Internal);
+
+        let (responses, mut stragglers) = broadcast_quorum::<u64>(
+            &client,
+            &nodes,
+            "/",
+            &(),
+            &peers(&nodes, false, Duration::from_secs(10)),
+            &span,
+        )
         .await
+        .unwrap();
+        assert_eq!(responses, [0, 0]);
+        assert!(stragglers.late_responses().is_empty());
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(stragglers.late_responses(), [200]);
+
+        let (responses, mut stragglers) = broadcast_quorum::<u64>(
+            &client,
+            &nodes,
+            "/",
+            &(),
+            &peers(&nodes, false, Duration::ZERO),
+            &span,
+        )
+        .await
+        .unwrap();
+        assert_eq!(responses, [0, 0]);
+        sleep(Duration::from_millis(400)).await;
+        assert!(stragglers.late_responses().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_quorum_fails_once_budget_is_used_up() {
+        let nodes = [
+            serve(Duration::ZERO).await,
+            unreachable().await,
+            unreachable().await,
+        ];
+        let client = new_client(Connector::default(), false);
+        let tracer = Tracer::new(None, None, &nodes[0]).await.unwrap();
+        let span = tracer.span("test", Kind::Internal);
+        let peers = peers(&nodes, false, Duration::ZERO);
+
+        // Every broadcast tries each node at least once, so by now the unreachable ones are
+        // considered down.
+        for _ in 0..3_u32 {
+            assert!(
+                broadcast_quorum::<u64>(&client, &nodes, "/", &(), &peers, &span)
+                    .await
+                    .is_err(),
+            );
+        }
+        let status = peers.status();
+        for (node, down) in nodes.iter().zip([false, true, true]) {
+            assert_eq!(
+                status
+                    .iter()
+                    .find(|status| status.address == *node)
+                    .unwrap()
+                    .down,
+                down,
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        let nodes = [
+            serve(Duration::ZERO).await,
+            serve(Duration::ZERO).await,
+            serve(Duration::ZERO).await,
+        ];
+        let tracer = Tracer::new(None, None, &nodes[0]).await.unwrap();
+        let span = tracer.span("test", Kind::Internal);
+
+        for http2 in [false, true] {
+            let connector = Connector::default();
+            let connections_opened = connector.opened();
+            let client = new_client(connector, http2);
+            let peers = peers(&nodes, http2, Duration::from_secs(10));
+            for _ in 0..3_u32 {
+                broadcast_quorum::<u64>(&client, &nodes, "/", &(), &peers, &span)
+                    .await
+                    .unwrap();
+
+                // Let the straggler finish, so its connection is free again.
+                sleep(Duration::from_millis(100)).await;
+            }
+            assert_eq!(connections_opened.load(Ordering::Relaxed), 3);
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use std::{io, sync::Arc};
+use tokio::{select, signal::ctrl_c, sync::watch};
+
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+
+// A handle for requesting a shutdown and observing whether one has been requested
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    // Create a handle which only shuts down when asked to.
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    // Start listening for SIGINT (or Ctrl+C) and, on Unix, SIGTERM.
+    pub fn listen() -> Self {
+        let shutdown = Self::new();
+
+        let signal_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            match wait_for_signal().await {
+                Ok(()) => signal_shutdown.request(),
+                Err(error) => error!("Unable to listen for shutdown signals. Reason: {error}"),
+            }
+        });
+
+        shutdown
+    }
+
+    // Ask the program to shut down.
+    pub fn request(&self) {
+        if !self.sender.send_replace(true) {
+            info!("Shutting down.");
+        }
+    }
+
+    // Determine whether a shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    // Wait until a shutdown has been requested.
+    pub async fn requested(&self) {
+        // The `unwrap` is safe since the sender is still alive.
+        self.sender
+            .subscribe()
+            .wait_for(|requested| *requested)
+            .await
+            .unwrap();
+    }
+
+    // Run a future to completion unless a shutdown is requested first, in which case the future is
+    // dropped and `None` is returned. Only futures which are safe to cancel at any await point
+    // should be passed in here; in particular, they must not write the state file.
+    pub async fn interrupt<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        select! {
+            output = future => Some(output),
+            () = self.requested() => None,
+        }
+    }
+}
+
+// Wait for a signal asking the program to shut down.
+async fn wait_for_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        select! {
+            result = ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    ctrl_c().await
+}
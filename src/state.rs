@@ -1,19 +1,32 @@
+use crate::address::Address;
 use serde::{Deserialize, Serialize};
-use std::{cmp::Ordering, io, net::SocketAddr, path::Path};
+use std::{
+    cmp::Ordering,
+    ffi::OsString,
+    io,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{self, AtomicU64},
+    },
+    time::Instant,
+};
 use tokio::{
-    fs::{File, create_dir_all},
+    fs::{File, create_dir_all, rename},
     io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex, RwLock},
 };
 
 // A representation of a proposal number
-#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct ProposalNumber {
     pub round: u64,
-    pub proposer_address: SocketAddr,
+    pub proposer_address: Address,
 }
 
-// We implement a custom ordering to ensure that round number takes precedence over proposer.
+// We implement a custom ordering to ensure that round number takes precedence over proposer
+// [tag:proposal_number_ord].
 impl Ord for ProposalNumber {
     fn cmp(&self, other: &Self) -> Ordering {
         if self.round == other.round {
@@ -32,17 +45,44 @@ impl PartialOrd for ProposalNumber {
 }
 
 // The part of the program's state that needs to be persisted
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Durable {
     pub next_round: u64,
     pub min_proposal_number: Option<ProposalNumber>,
     pub accepted_proposal: Option<(ProposalNumber, String)>,
+
+    // The fast ballot this node was told to accept any value in, if any. State files written before
+    // Fast Paxos was supported don't have this field.
+    #[serde(default)]
+    pub fast_ballot: Option<ProposalNumber>,
 }
 
 // The part of the program's state that doesn't need to be persisted
-#[derive(Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Volatile {
-    pub chosen_value: Option<String>,
+    pub chosen_proposal: Option<(ProposalNumber, String)>,
+
+    // The leader lease this node granted, if any. It's measured with the local clock, so it means
+    // nothing to other nodes or to later runs of the program.
+    #[serde(skip)]
+    pub leader_lease: Option<LeaseGrant>,
+
+    // How far the state file lags behind the durable state
+    #[serde(skip)]
+    pub commits: Commits,
+}
+
+// A leader lease granted by a node. Until it expires, the node refuses proposals from any node
+// other than the holder. An unknown holder stands for a lease the node may have granted before it
+// restarted, so every proposal is refused until that lease would have expired.
+#[derive(Clone)]
+pub struct LeaseGrant {
+    pub holder: Option<Address>,
+    pub expires_at: Instant,
+
+    // Whether the node refused another node because of this lease. If so, the lease isn't renewed,
+    // so that other node gets a chance once it expires.
+    pub contended: bool,
 }
 
 // Return the state in which the program starts.
@@ -52,24 +92,97 @@ pub fn initial() -> (Durable, Volatile) {
             next_round: 0,
             min_proposal_number: None,
             accepted_proposal: None,
+            fast_ballot: None,
+        },
+        Volatile {
+            chosen_proposal: None,
+            leader_lease: None,
+            commits: Commits::default(),
         },
-        Volatile { chosen_value: None },
     )
 }
 
-// Write the state to a file.
+// The changes to the durable state which an acceptor has made, and how many of them have been
+// written to the state file. Acceptors don't write the file while the state is locked for a change.
+// Instead, they count the change, release the lock, and wait for it to be committed before they
+// reply. The file is written by one request at a time, and each write covers every change made
+// while the previous one was in progress, so a burst of concurrent requests shares one fsync
+// [tag:group_commit].
+#[derive(Clone, Default)]
+pub struct Commits {
+    changes: u64,
+    written: Arc<AtomicU64>,
+    writes: Arc<AtomicU64>,
+    writing: Arc<Mutex<()>>,
+}
+
+impl Commits {
+    // Count a change to the durable state. This must be called while the state is locked.
+    pub fn change(&mut self) {
+        self.changes += 1;
+    }
+
+    // The number of changes made so far, which a request waits for before it replies, since it may
+    // have seen changes which haven't been written yet
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    // How many times the state file was written to commit changes
+    pub fn writes(&self) -> u64 {
+        self.writes.load(atomic::Ordering::Relaxed)
+    }
+}
+
+// Wait until the given number of changes to the durable state have been written to the file,
+// writing it if no other request is already doing so [ref:group_commit]. The file is written while
+// the state is locked for reading, so no change is made halfway through a write.
+pub async fn commit(
+    state: &RwLock<(Durable, Volatile)>,
+    changes: u64,
+    path: &Path,
+) -> io::Result<()> {
+    let commits = state.read().await.1.commits.clone();
+    if commits.written.load(atomic::Ordering::Acquire) >= changes {
+        return Ok(());
+    }
+
+    // Wait for the write in progress, if any. It may have covered the changes.
+    let _writing = commits.writing.lock().await;
+    if commits.written.load(atomic::Ordering::Acquire) >= changes {
+        return Ok(());
+    }
+
+    let guard = state.read().await;
+    write(&guard.0, path).await?;
+    commits.writes.fetch_add(1, atomic::Ordering::Relaxed);
+    commits
+        .written
+        .store(guard.1.commits.changes, atomic::Ordering::Release);
+    Ok(())
+}
+
+// Write the state to a file. The new file replaces the old one atomically, so the state is never
+// torn, even if the write is abandoned or the program crashes in the middle of it.
 pub async fn write(state: &Durable, path: &Path) -> io::Result<()> {
     // The `unwrap` is safe because serialization should never fail.
     let payload = serde_json::to_vec(&state).unwrap();
 
-    // The `unwrap` is safe due to [ref:data_file_path_has_parent].
+    // The `unwrap`s are safe due to [ref:data_file_path_has_parent]. The temporary file starts with
+    // `.`, so it can't be mistaken for the state file of a named instance.
     let parent = path.parent().unwrap().to_owned();
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(path.file_name().unwrap());
+    temporary_name.push(".tmp");
+    let temporary_path = parent.join(temporary_name);
 
-    // Create the directories if necessary and write the file.
+    // Create the directories if necessary, write the temporary file, and move it into place.
     create_dir_all(parent).await?;
-    let mut file = File::create(path).await?;
+    let mut file = File::create(&temporary_path).await?;
     file.write_all(&payload).await?;
-    file.sync_all().await
+    file.sync_all().await?;
+    drop(file);
+    rename(&temporary_path, path).await
 }
 
 // Read the state from a file.
@@ -94,19 +207,27 @@ pub async fn read(path: &Path) -> io::Result<Durable> {
 
 #[cfg(test)]
 mod tests {
-    use crate::state::ProposalNumber;
+    use crate::{
+        address::Address,
+        state::{ProposalNumber, commit, initial, read},
+        test_dir::TestDir,
+    };
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use tokio::sync::RwLock;
 
     #[test]
     fn proposal_ord_round() {
         let pn0 = ProposalNumber {
             round: 0,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 8081),
+            proposer_address: Address::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+                8081,
+            )),
         };
 
         let pn1 = ProposalNumber {
             round: 1,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
         };
 
         assert!(pn1 > pn0);
@@ -116,12 +237,15 @@ mod tests {
     fn proposal_ord_proposer_ip() {
         let pn0 = ProposalNumber {
             round: 0,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081),
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081)),
         };
 
         let pn1 = ProposalNumber {
             round: 0,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 8080),
+            proposer_address: Address::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+                8080,
+            )),
         };
 
         assert!(pn1 > pn0);
@@ -131,14 +255,45 @@ mod tests {
     fn proposal_ord_proposer_port() {
         let pn0 = ProposalNumber {
             round: 0,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
         };
 
         let pn1 = ProposalNumber {
             round: 0,
-            proposer_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081),
+            proposer_address: Address::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081)),
         };
 
         assert!(pn1 > pn0);
     }
+
+    #[tokio::test]
+    async fn concurrent_changes_share_a_write() {
+        let data_dir = TestDir::new("state");
+        let data_file_path = data_dir.path().join("127.0.0.1-3000");
+        let state = RwLock::new(initial());
+
+        // Make a few changes before any of them is committed.
+        for round in 1..=3_u64 {
+            let mut guard = state.write().await;
+            guard.0.next_round = round;
+            guard.1.commits.change();
+        }
+
+        // The first request to commit writes every change, so the others don't have to.
+        let (first, second, third) = tokio::join!(
+            commit(&state, 1, &data_file_path),
+            commit(&state, 3, &data_file_path),
+            commit(&state, 2, &data_file_path),
+        );
+        first.unwrap();
+        second.unwrap();
+        third.unwrap();
+        assert_eq!(state.read().await.1.commits.writes(), 1);
+        assert_eq!(read(&data_file_path).await.unwrap().next_round, 3);
+
+        // Requests which didn't change anything don't write anything.
+        let changes = state.read().await.1.commits.changes();
+        commit(&state, changes, &data_file_path).await.unwrap();
+        assert_eq!(state.read().await.1.commits.writes(), 1);
+    }
 }
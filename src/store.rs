@@ -0,0 +1,110 @@
+use crate::{
+    instances::{Instances, validate_key},
+    node::Node,
+    proposer::{QuorumRead, propose, read_quorum},
+};
+use serde::Serialize;
+use std::io;
+
+// The endpoint for listing the keys. The endpoint for each key is below it, like `/kv/foo`.
+pub const KV_ENDPOINT: &str = "/kv";
+
+// A key and the value chosen for it, if known
+#[derive(Serialize)]
+pub struct Entry {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+// Determine which key an endpoint is for, if any.
+pub fn key(path: &str) -> Option<Result<&str, String>> {
+    let key = path.strip_prefix(KV_ENDPOINT)?.strip_prefix('/')?;
+    Some(validate_key(key).map(|()| key))
+}
+
+// Propose a value for a key. Each key is a named instance, so only the first value proposed for it
+// can be chosen, no matter which node it's proposed to. Returns the chosen value, which may be
+// another one, or nothing if a shutdown was requested before a value was chosen.
+pub async fn put(node: &Node, key: &str, value: &str) -> io::Result<Option<String>> {
+    let instances = &node.instances;
+    let (state, data_file_path) = instances.get(key).await?;
+    if let Some((_, chosen_value)) = &state.read().await.1.chosen_proposal {
+        return Ok(Some(chosen_value.clone()));
+    }
+
+    // Only compacted instances lack a state file, and their values are always known.
+    let data_file_path = data_file_path.unwrap_or_else(|| instances.path(key));
+    Ok(propose(
+        node,
+        state.clone(),
+        &data_file_path,
+        &instances.endpoint_prefix(key),
+        Some(value),
+    )
+    .await?
+    .map(|(_, value)| value))
+}
+
+// Determine the value chosen for a key, if any. If this node doesn't know it, it asks a quorum
+// without making them promise anything, so reading a key nobody has written doesn't create state
+// for it anywhere. Only if the quorum disagrees does it run a round of the protocol without a value
+// of its own, which finishes choosing any value a quorum has accepted. Returns nothing if a
+// shutdown was requested first, or an error if a quorum couldn't be reached.
+pub async fn get(node: &Node, key: &str) -> Option<io::Result<Option<String>>> {
+    let instances = &node.instances;
+    let (state, data_file_path) = match instances.get(key).await {
+        Ok(instance) => instance,
+        Err(error) => return Some(Err(error)),
+    };
+    if let Some((_, chosen_value)) = &state.read().await.1.chosen_proposal {
+        return Some(Ok(Some(chosen_value.clone())));
+    }
+
+    let endpoint_prefix = instances.endpoint_prefix(key);
+    match read_quorum(node, &endpoint_prefix, false).await? {
+        Err(error) => Some(Err(error)),
+        Ok(QuorumRead::Chosen((_, value))) => Some(Ok(Some(value))),
+        Ok(QuorumRead::NothingChosen) => Some(Ok(None)),
+        Ok(QuorumRead::Unknown) => {
+            // Only compacted instances lack a state file, and their values are always known.
+            let data_file_path = data_file_path.unwrap_or_else(|| instances.path(key));
+            let chosen_proposal =
+                propose(node, state.clone(), &data_file_path, &endpoint_prefix, None).await;
+
+            // Without a value of its own, the proposer only stops before the value is known if a
+            // quorum it prepared had accepted nothing, in which case nothing had been chosen.
+            match chosen_proposal {
+                Ok(None) if node.shutdown.is_requested() => None,
+                chosen_proposal => Some(
+                    chosen_proposal.map(|chosen_proposal| chosen_proposal.map(|(_, value)| value)),
+                ),
+            }
+        }
+    }
+}
+
+// List the keys this node knows about, along with the values chosen for them if this node knows
+// them.
+pub async fn list(instances: &Instances) -> io::Result<Vec<Entry>> {
+    Ok(instances
+        .list()
+        .await?
+        .into_iter()
+        .map(|(key, value)| Entry { key, value })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::key;
+
+    #[test]
+    fn keys() {
+        assert_eq!(key("/kv/job-42"), Some(Ok("job-42")));
+        assert!(matches!(key("/kv/a/b"), Some(Err(_))));
+        assert!(matches!(key("/kv/"), Some(Err(_))));
+        assert_eq!(key("/kv"), None);
+        assert_eq!(key("/kvfoo"), None);
+        assert_eq!(key("/state"), None);
+    }
+}
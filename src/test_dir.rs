@@ -0,0 +1,42 @@
+use std::{
+    env::temp_dir,
+    fs::{create_dir_all, remove_dir_all},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Distinguishes the directories created by this process
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// A temporary directory for a test. It's deleted when it's dropped, so it doesn't stick around even
+// if the test fails.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    // Create an empty directory whose name includes the given one.
+    pub fn new(name: &str) -> Self {
+        let path = temp_dir().join(format!(
+            "paxos-{name}-test-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        ));
+        create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    // The path of the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        // The test may have deleted the directory already, and there's nothing to do if it can't
+        // be deleted.
+        let _ = remove_dir_all(&self.path);
+    }
+}
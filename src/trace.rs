@@ -0,0 +1,415 @@
+use crate::{
+    address::Address,
+    rpc::{HttpClient, new_client},
+    transport::Connector,
+};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Method, Request, header::CONTENT_TYPE};
+use rand::RngExt;
+use serde_json::{Value, json};
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    select,
+    sync::{mpsc, oneshot},
+    time::{interval, timeout},
+};
+
+// The HTTP header used to propagate trace context, as specified by W3C Trace Context
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+// Duration constants
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How many ended spans can wait to be exported. Spans which end while the queue is full are dropped,
+// so a slow or unreachable collector can't make the node run out of memory.
+const QUEUE_CAPACITY: usize = 4096;
+
+// The role a span plays in a trace, with the numeric codes used by OTLP
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+// Messages sent to the exporter task
+enum Message {
+    Span(Value),
+    Flush(oneshot::Sender<()>),
+}
+
+// A handle for starting spans. If tracing is disabled, spans are still created (so the code which
+// uses them doesn't need to care), but they're discarded when they end.
+#[derive(Clone)]
+pub struct Tracer {
+    sender: Option<mpsc::Sender<Message>>,
+}
+
+impl Tracer {
+    // Start exporting spans to a file and/or an OTLP/HTTP endpoint, if either is given.
+    pub async fn new(
+        trace_file_path: Option<&Path>,
+        otlp_endpoint: Option<&str>,
+        address: &Address,
+    ) -> io::Result<Self> {
+        if trace_file_path.is_none() && otlp_endpoint.is_none() {
+            return Ok(Self { sender: None });
+        }
+
+        // Open the file now so problems with it are reported at startup.
+        let file = match trace_file_path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|error| {
+                        io::Error::new(
+                            error.kind(),
+                            format!(
+                                "Unable to open trace file `{}`. Reason: {}",
+                                path.to_string_lossy(),
+                                error,
+                            ),
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+
+        // Likewise, validate the endpoint now.
+        let endpoint = match otlp_endpoint {
+            Some(endpoint) => Some(endpoint.parse().map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{endpoint}` is not a valid OTLP endpoint. Reason: {error}"),
+                )
+            })?),
+            None => None,
+        };
+
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(export(receiver, file, endpoint, address.to_string()));
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+
+    // Start a new trace.
+    pub fn span(&self, name: impl Into<String>, kind: Kind) -> Span {
+        let mut rng = rand::rng();
+        Span::new(self.clone(), name.into(), kind, rng.random(), None)
+    }
+
+    // Start a span on behalf of a remote parent, given the value of its `traceparent` header. A new
+    // trace is started if the header is missing or malformed.
+    pub fn remote_span(
+        &self,
+        name: impl Into<String>,
+        kind: Kind,
+        traceparent: Option<&str>,
+    ) -> Span {
+        match traceparent.and_then(parse_traceparent) {
+            Some((trace_id, parent_id)) => {
+                Span::new(self.clone(), name.into(), kind, trace_id, Some(parent_id))
+            }
+            None => self.span(name, kind),
+        }
+    }
+
+    // Wait for the spans which have ended so far to be exported, giving up after a timeout so an
+    // unreachable collector can't hold up shutting down.
+    pub async fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            let (flushed_sender, flushed_receiver) = oneshot::channel();
+            let flushed = async {
+                if sender.send(Message::Flush(flushed_sender)).await.is_ok() {
+                    flushed_receiver.await.unwrap_or(());
+                }
+            };
+            if timeout(FLUSH_TIMEOUT, flushed).await.is_err() {
+                warn!("Timed out waiting for spans to be exported.");
+            }
+        }
+    }
+}
+
+// A span, which is exported when dropped
+pub struct Span {
+    tracer: Tracer,
+    name: String,
+    kind: Kind,
+    trace_id: u128,
+    id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    fn new(
+        tracer: Tracer,
+        name: String,
+        kind: Kind,
+        trace_id: u128,
+        parent_id: Option<u64>,
+    ) -> Self {
+        Self {
+            tracer,
+            name,
+            kind,
+            trace_id,
+            id: rand::rng().random(),
+            parent_id,
+            start: SystemTime::now(),
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    // Start a span which is a child of this one.
+    pub fn child(&self, name: impl Into<String>, kind: Kind) -> Self {
+        self.reference().child(name, kind)
+    }
+
+    // Refer to this span, e.g., to start children of it from a task which may outlive it.
+    pub fn reference(&self) -> SpanRef {
+        SpanRef {
+            tracer: self.tracer.clone(),
+            trace_id: self.trace_id,
+            id: self.id,
+        }
+    }
+
+    // Attach an attribute to the span.
+    pub fn set<T: ToString + ?Sized>(&mut self, key: &'static str, value: &T) {
+        self.attributes.push((key, value.to_string()));
+    }
+
+    // Mark the span as having failed.
+    pub fn fail<T: ToString + ?Sized>(&mut self, error: &T) {
+        self.error = Some(error.to_string());
+    }
+
+    // Return the value of the `traceparent` header for requests made on behalf of this span, unless
+    // tracing is disabled.
+    pub fn traceparent(&self) -> Option<String> {
+        self.tracer
+            .sender
+            .as_ref()
+            .map(|_| format!("00-{:032x}-{:016x}-01", self.trace_id, self.id))
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(sender) = &self.tracer.sender {
+            // Sending only fails if the queue is full, in which case the span is dropped, or if the
+            // exporter is gone, in which case there's nothing to do.
+            let _ = sender.try_send(Message::Span(json!({
+                "traceId": format!("{:032x}", self.trace_id),
+                "spanId": format!("{:016x}", self.id),
+                "parentSpanId": self
+                    .parent_id
+                    .map_or_else(String::new, |id| format!("{id:016x}")),
+                "name": self.name,
+                "kind": self.kind as u8,
+                "startTimeUnixNano": unix_nanos(self.start),
+                "endTimeUnixNano": unix_nanos(SystemTime::now()),
+                "attributes": self
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect::<Vec<_>>(),
+                "status": match &self.error {
+                    Some(error) => json!({ "code": 2_u8, "message": error }),
+                    None => json!({}),
+                },
+            })));
+        }
+    }
+}
+
+// A reference to a span, which can be used to start children of it
+#[derive(Clone)]
+pub struct SpanRef {
+    tracer: Tracer,
+    trace_id: u128,
+    id: u64,
+}
+
+impl SpanRef {
+    // Start a span which is a child of the referenced one.
+    pub fn child(&self, name: impl Into<String>, kind: Kind) -> Span {
+        Span::new(
+            self.tracer.clone(),
+            name.into(),
+            kind,
+            self.trace_id,
+            Some(self.id),
+        )
+    }
+}
+
+// Parse a `traceparent` header into a trace ID and a parent span ID.
+fn parse_traceparent(traceparent: &str) -> Option<(u128, u64)> {
+    let mut parts = traceparent.trim().split('-');
+    let _version = parts.next()?;
+    let trace_id = parts.next().filter(|part| part.len() == 32)?;
+    let span_id = parts.next().filter(|part| part.len() == 16)?;
+    Some((
+        u128::from_str_radix(trace_id, 16).ok()?,
+        u64::from_str_radix(span_id, 16).ok()?,
+    ))
+}
+
+// Format a time as a string containing the number of nanoseconds since the Unix epoch, as OTLP
+// expects.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos())
+        .to_string()
+}
+
+// Encode a string attribute for OTLP.
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+// Export spans in batches. Each batch is encoded as an OTLP/JSON `ExportTraceServiceRequest`, which
+// is POSTed to the endpoint and written to the file as a single line. That's the format read by the
+// OpenTelemetry Collector's `otlpjsonfile` receiver.
+async fn export(
+    mut receiver: mpsc::Receiver<Message>,
+    mut file: Option<File>,
+    endpoint: Option<hyper::Uri>,
+    address: String,
+) {
+    let client = new_client(Connector::default(), false);
+    let mut spans = vec![];
+    let mut ticks = interval(EXPORT_INTERVAL);
+
+    loop {
+        let (flushed, closed) = select! {
+            message = receiver.recv() => match message {
+                Some(Message::Span(span)) => {
+                    spans.push(span);
+                    continue;
+                }
+                Some(Message::Flush(flushed)) => (Some(flushed), false),
+                None => (None, true),
+            },
+            _ = ticks.tick() => (None, false),
+        };
+
+        if !spans.is_empty() {
+            // The `unwrap` is safe because serialization should never fail.
+            let batch = serde_json::to_vec(&json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [
+                            attribute("service.name", env!("CARGO_PKG_NAME")),
+                            attribute("service.version", env!("CARGO_PKG_VERSION")),
+                            attribute("service.instance.id", &address),
+                        ],
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": env!("CARGO_PKG_NAME") },
+                        "spans": spans.split_off(0),
+                    }],
+                }],
+            }))
+            .unwrap();
+
+            if let Some(file) = &mut file
+                && let Err(error) = write_batch(file, &batch).await
+            {
+                warn!("Unable to write to trace file. Reason: {error}");
+            }
+
+            if let Some(endpoint) = &endpoint
+                && let Err(error) = post_batch(&client, endpoint, batch).await
+            {
+                warn!("Unable to send spans to `{endpoint}`. Reason: {error}");
+            }
+        }
+
+        if let Some(flushed) = flushed {
+            flushed.send(()).unwrap_or(());
+        }
+
+        if closed {
+            return;
+        }
+    }
+}
+
+// Append a batch of spans to the trace file.
+async fn write_batch(file: &mut File, batch: &[u8]) -> io::Result<()> {
+    file.write_all(batch).await?;
+    file.write_all(b"\n").await?;
+    file.flush().await
+}
+
+// Send a batch of spans to an OTLP/HTTP endpoint.
+async fn post_batch(client: &HttpClient, endpoint: &hyper::Uri, batch: Vec<u8>) -> io::Result<()> {
+    let response = timeout(
+        EXPORT_TIMEOUT,
+        client.request(
+            Request::builder()
+                .method(Method::POST)
+                .uri(endpoint)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Full::new(Bytes::from(batch)))
+                .unwrap(), // Safe since we constructed a well-formed request
+        ),
+    )
+    .await
+    .map_err(|_| io::Error::other("Timed out sending request."))?
+    .map_err(|error| io::Error::other(format!("Unable to send request. Reason: {error}")))?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "The collector responded with status {}.",
+            response.status(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::parse_traceparent;
+
+    #[test]
+    fn parse_valid_traceparent() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some((
+                0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736,
+                0x00f0_67aa_0ba9_02b7,
+            )),
+        );
+    }
+
+    #[test]
+    fn parse_invalid_traceparent() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(parse_traceparent("garbage"), None);
+    }
+}
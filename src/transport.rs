@@ -0,0 +1,290 @@
+use crate::address::Address;
+use hyper::Uri;
+use hyper_util::{
+    client::legacy::connect::{Connected, Connection, HttpConnector},
+    rt::TokioIo,
+};
+use std::{
+    fmt::Write,
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tower_service::Service;
+
+#[cfg(unix)]
+use {
+    std::{os::unix::fs::FileTypeExt, path::PathBuf},
+    tokio::{
+        fs::{remove_file, symlink_metadata},
+        net::{UnixListener, UnixStream},
+    },
+};
+
+// The URI scheme used for requests to nodes listening on Unix domain sockets
+const UNIX_SCHEME: &str = "unix";
+
+// Construct the URI of an endpoint on a node. A socket path can't appear in the authority of a URI
+// directly, so it's hex-encoded [tag:unix_socket_uri].
+pub fn uri(node: &Address, endpoint: &str) -> String {
+    match node {
+        Address::Tcp(address) => format!("http://{address}{endpoint}"),
+        Address::Unix(path) => {
+            let mut uri = format!("{UNIX_SCHEME}://");
+            for byte in path.to_string_lossy().bytes() {
+                // Writing to a `String` can't fail.
+                let _ = write!(uri, "{byte:02x}");
+            }
+            uri.push_str(endpoint);
+            uri
+        }
+    }
+}
+
+// Recover the socket path from a URI constructed by `uri` [ref:unix_socket_uri].
+#[cfg(unix)]
+fn socket_path(uri: &Uri) -> io::Result<PathBuf> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`{uri}` does not refer to a Unix domain socket."),
+        )
+    };
+    let host = uri.host().ok_or_else(invalid)?;
+    let bytes = (0..host.len())
+        .step_by(2)
+        .map(|i| {
+            host.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| invalid())
+}
+
+// A connection to or from a node
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// This macro forwards a method call to whichever stream is inside a pinned `Stream`.
+macro_rules! forward {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match $self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).$method($($arg),*),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).$method($($arg),*),
+        }
+    };
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        forward!(self.poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        forward!(self.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        forward!(self.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        forward!(self.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        forward!(self.poll_shutdown(cx))
+    }
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.connected(),
+        }
+    }
+}
+
+// A listener which accepts connections from other nodes
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // Start listening on the given address.
+    pub async fn bind(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(address) => TcpListener::bind(address).await.map(Self::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                // A socket file left behind by a previous run would prevent binding, so remove it
+                // unless some other process is still listening on it.
+                if let Ok(metadata) = symlink_metadata(path).await
+                    && metadata.file_type().is_socket()
+                    && UnixStream::connect(path).await.is_err()
+                {
+                    remove_file(path).await?;
+                }
+
+                UnixListener::bind(path).map(Self::Unix)
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    // Wait for the next incoming connection.
+    pub async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                if let Err(error) = stream.set_nodelay(true) {
+                    debug!("Unable to disable Nagle's algorithm for `{address}`. Reason: {error}");
+                }
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+
+// A connector for the HTTP client which understands the URIs constructed by `uri`. It counts the
+// connections it opens, so the effect of reusing them can be measured.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    opened: Arc<AtomicU64>,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        // The RPCs are small, so they shouldn't wait around to be coalesced.
+        let mut http = HttpConnector::new();
+        http.set_nodelay(true);
+        Self {
+            http,
+            opened: Arc::default(),
+        }
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = TokioIo<Stream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TokioIo<Stream>>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.http.poll_ready(cx).map_err(io::Error::other)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.connect(uri);
+        let opened = self.opened.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            opened.fetch_add(1, Ordering::Relaxed);
+            Ok(stream)
+        })
+    }
+}
+
+impl Connector {
+    // Return a counter of the connections opened by this connector and its clones.
+    pub fn opened(&self) -> Arc<AtomicU64> {
+        self.opened.clone()
+    }
+
+    // Open a connection to the node a URI refers to.
+    fn connect(&mut self, uri: Uri) -> <Self as Service<Uri>>::Future {
+        if uri.scheme_str() == Some(UNIX_SCHEME) {
+            #[cfg(unix)]
+            return Box::pin(async move {
+                UnixStream::connect(socket_path(&uri)?)
+                    .await
+                    .map(|stream| TokioIo::new(Stream::Unix(stream)))
+            });
+
+            #[cfg(not(unix))]
+            return Box::pin(async { Err(unix_unsupported()) });
+        }
+
+        let connecting = self.http.call(uri);
+        Box::pin(async move {
+            connecting
+                .await
+                .map(|stream| TokioIo::new(Stream::Tcp(stream.into_inner())))
+                .map_err(io::Error::other)
+        })
+    }
+}
+
+// The error reported when a Unix domain socket is used on a platform which doesn't support them
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform.",
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{
+        address::Address,
+        transport::{socket_path, uri},
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn unix_socket_uri_round_trip() {
+        let path = PathBuf::from("/tmp/paxos cluster/node-0.sock");
+        let uri = uri(&Address::Unix(path.clone()), "/prepare");
+        assert_eq!(socket_path(&uri.parse().unwrap()).unwrap(), path);
+    }
+}